@@ -6,7 +6,7 @@ use zugzug::*;
 #[derive(StructOpt)]
 struct Opt {
   #[structopt(short = "c", long = "channel", env = "PN_CHANNEL")]
-  channel: String,
+  channel: Option<String>,
   #[structopt(short = "a", long = "auth_key", env = "PN_AUTH_KEY")]
   auth_key: String,
   #[structopt(short = "p", long = "publish_key", env = "PN_PUBLISH_KEY")]
@@ -14,7 +14,7 @@ struct Opt {
   #[structopt(short = "s", long = "subscribe_key", env = "PN_SUBSCRIBE_KEY")]
   subscribe_key: String,
   #[structopt(short = "g", long = "group", env = "PN_GROUP")]
-  group: String,
+  group: Option<String>,
   #[structopt(short = "u", long = "client_uuid", env = "PN_CLIENT_UUID")]
   client_uuid: String,
 }
@@ -31,12 +31,13 @@ fn main() {
     publish_key: opt.publish_key,
     subscribe_key: opt.subscribe_key,
     client_uuid: opt.client_uuid,
+    ..Default::default()
   });
 
   let channel = opt.channel;
   let group = opt.group;
 
-  let task = client.subscribe::<Stuff>(&channel, &group);
+  let task = client.subscribe::<Stuff>(channel.as_ref().map(String::as_str), group.as_ref().map(String::as_str));
 
   tokio::run(task.map_err(|e| {
     println!("An error {:?}", e);
@@ -13,8 +13,6 @@ struct Opt {
   publish_key: String,
   #[structopt(short = "s", long = "subscribe_key", env = "PN_SUBSCRIBE_KEY")]
   subscribe_key: String,
-  #[structopt(short = "g", long = "group", env = "PN_GROUP")]
-  group: String,
   #[structopt(short = "u", long = "client_uuid", env = "PN_CLIENT_UUID")]
   client_uuid: String,
 }
@@ -31,10 +29,10 @@ fn main() {
     publish_key: opt.publish_key,
     subscribe_key: opt.subscribe_key,
     client_uuid: opt.client_uuid,
+    ..Default::default()
   });
 
   let channel = opt.channel;
-  let group = opt.group;
 
   let mut i = 0;
   loop {
@@ -42,7 +40,6 @@ fn main() {
     client
       .publish(
         &channel,
-        &group,
         Stuff {
           message: format!("#{}", i),
         },
@@ -0,0 +1,127 @@
+use serde::Deserialize;
+use std::ffi::CString;
+
+use zugzug_sys::callback::*;
+
+use crate::{as_ptr_or_null, join_names, parse_json, Client, ClientError, PubNubErrorKind, TransactionFuture};
+
+/// The channels registered to a channel group.
+#[derive(Eq, PartialEq, Clone, Debug, Deserialize)]
+pub struct ChannelGroup {
+  pub group: String,
+  pub channels: Vec<String>,
+}
+
+// The envelope the channel registry wraps every response in.
+#[derive(Deserialize)]
+struct ChannelRegistryResponse<P> {
+  status: u16,
+  #[serde(default)]
+  error: bool,
+  payload: Option<P>,
+}
+
+impl<P> ChannelRegistryResponse<P> {
+  fn into_result(self) -> Result<Option<P>, ClientError> {
+    if self.error || self.status != 200 {
+      Err(ClientError::PubNub {
+        kind: PubNubErrorKind::ChannelRegistryError,
+      })
+    } else {
+      Ok(self.payload)
+    }
+  }
+}
+
+unsafe fn parse_ack(pb: *mut pubnub_t) -> Result<(), ClientError> {
+  parse_json::<ChannelRegistryResponse<serde_json::Value>>(pb)?.into_result()?;
+  Ok(())
+}
+
+unsafe fn parse_channel_group(pb: *mut pubnub_t) -> Result<ChannelGroup, ClientError> {
+  parse_json::<ChannelRegistryResponse<ChannelGroup>>(pb)?
+    .into_result()?
+    .ok_or(ClientError::NoResponse)
+}
+
+impl Client {
+  /// Adds `channels` to `group`, creating the group if it does not exist yet.
+  pub fn add_channels_to_group(&self, group: &str, channels: &[&str]) -> TransactionFuture<()> {
+    let group_c = CString::new(group).expect("UTF-8 doesn't include nul");
+
+    TransactionFuture::new(
+      self.channel_config(join_names(channels), Some(group_c)),
+      pubnub_trans_PBTT_ADD_CHANNEL_TO_GROUP,
+      Box::new(|ctx, config| unsafe {
+        pubnub_add_channel_to_group(ctx, as_ptr_or_null(&config.channel), as_ptr_or_null(&config.group))
+      }),
+      Box::new(|pb| unsafe { parse_ack(pb) }),
+    )
+  }
+
+  /// Removes `channels` from `group`.
+  pub fn remove_channels_from_group(&self, group: &str, channels: &[&str]) -> TransactionFuture<()> {
+    let group_c = CString::new(group).expect("UTF-8 doesn't include nul");
+
+    TransactionFuture::new(
+      self.channel_config(join_names(channels), Some(group_c)),
+      pubnub_trans_PBTT_REMOVE_CHANNEL_FROM_GROUP,
+      Box::new(|ctx, config| unsafe {
+        pubnub_remove_channel_from_group(ctx, as_ptr_or_null(&config.channel), as_ptr_or_null(&config.group))
+      }),
+      Box::new(|pb| unsafe { parse_ack(pb) }),
+    )
+  }
+
+  /// Lists the channels registered to `group`.
+  pub fn list_group_channels(&self, group: &str) -> TransactionFuture<ChannelGroup> {
+    let group_c = CString::new(group).expect("UTF-8 doesn't include nul");
+
+    TransactionFuture::new(
+      self.channel_config(None, Some(group_c)),
+      pubnub_trans_PBTT_LIST_CHANNEL_GROUP,
+      Box::new(|ctx, config| unsafe { pubnub_list_channel_group(ctx, as_ptr_or_null(&config.group)) }),
+      Box::new(|pb| unsafe { parse_channel_group(pb) }),
+    )
+  }
+
+  /// Deletes `group` along with all of its channel registrations.
+  pub fn delete_group(&self, group: &str) -> TransactionFuture<()> {
+    let group_c = CString::new(group).expect("UTF-8 doesn't include nul");
+
+    TransactionFuture::new(
+      self.channel_config(None, Some(group_c)),
+      pubnub_trans_PBTT_REMOVE_CHANNEL_GROUP,
+      Box::new(|ctx, config| unsafe { pubnub_remove_channel_group(ctx, as_ptr_or_null(&config.group)) }),
+      Box::new(|pb| unsafe { parse_ack(pb) }),
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parses_channel_listing() {
+    let json =
+      r#"{"status":200,"payload":{"channels":["a","b"],"group":"g"},"service":"channel-registry","error":false}"#;
+    let res: ChannelRegistryResponse<ChannelGroup> = serde_json::from_str(json).unwrap();
+
+    assert_eq!(
+      res.into_result().unwrap(),
+      Some(ChannelGroup {
+        group: "g".to_owned(),
+        channels: vec!["a".to_owned(), "b".to_owned()],
+      })
+    );
+  }
+
+  #[test]
+  fn rejects_error_response() {
+    let json = r#"{"status":400,"message":"Invalid group","service":"channel-registry","error":true}"#;
+    let res: ChannelRegistryResponse<ChannelGroup> = serde_json::from_str(json).unwrap();
+
+    assert!(res.into_result().is_err());
+  }
+}
@@ -0,0 +1,150 @@
+use serde::de::{Error as _, IgnoredAny};
+use serde_json::Value;
+
+/// Turns messages into the JSON that PubNub carries, and back.  Messages reach a codec already serialized to a
+/// `serde_json::Value`, and leave it as one to be deserialized into the message type, so a codec only deals with
+/// the wire format.  Chosen with `Client::with_codec`; the default is `Json`.
+///
+/// Errors are `serde_json::Error`s (made with `serde::de::Error::custom` if need be), so that messages a codec can't
+/// decode are reported, and dead-lettered, like any other message that can't be deserialized.
+pub trait Codec: std::fmt::Debug + Send + Sync {
+  /// Encodes a message to publish.  PubNub only accepts JSON, so the result must be valid JSON.
+  fn encode(&self, value: Value) -> Result<String, serde_json::Error>;
+  /// Decodes a received message.
+  fn decode(&self, message: &str) -> Result<Value, serde_json::Error>;
+}
+
+/// Messages are published as JSON.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct Json;
+
+impl Codec for Json {
+  fn encode(&self, value: Value) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&value)
+  }
+
+  fn decode(&self, message: &str) -> Result<Value, serde_json::Error> {
+    serde_json::from_str(message)
+  }
+}
+
+/// Messages are strings of JSON that are published as they are, for payloads that are already encoded.  Each
+/// received message is the JSON PubNub delivered, undecoded.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct RawString;
+
+impl Codec for RawString {
+  fn encode(&self, value: Value) -> Result<String, serde_json::Error> {
+    match value {
+      Value::String(s) => {
+        // PubNub would reject it anyway, but this way the publish fails without being sent.
+        serde_json::from_str::<IgnoredAny>(&s)?;
+        Ok(s)
+      }
+      other => Err(serde_json::Error::custom(format!("expected a string, found {}", other))),
+    }
+  }
+
+  fn decode(&self, message: &str) -> Result<Value, serde_json::Error> {
+    Ok(Value::String(message.to_owned()))
+  }
+}
+
+/// Messages are byte buffers (e.g. `Vec<u8>` holding a protobuf frame), published as base64 strings.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct Base64;
+
+impl Codec for Base64 {
+  fn encode(&self, value: Value) -> Result<String, serde_json::Error> {
+    encode_base64(&bytes(value)?)
+  }
+
+  fn decode(&self, message: &str) -> Result<Value, serde_json::Error> {
+    let bytes = decode_base64(message)?;
+    Ok(Value::Array(bytes.into_iter().map(Value::from).collect()))
+  }
+}
+
+/// Messages are encoded as CBOR, published as base64 strings.
+#[cfg(feature = "cbor")]
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+  fn encode(&self, value: Value) -> Result<String, serde_json::Error> {
+    encode_base64(&serde_cbor::to_vec(&value).map_err(serde_json::Error::custom)?)
+  }
+
+  fn decode(&self, message: &str) -> Result<Value, serde_json::Error> {
+    let bytes = decode_base64(message)?;
+    serde_transcode::transcode(
+      &mut serde_cbor::Deserializer::from_slice(&bytes),
+      serde_json::value::Serializer,
+    )
+  }
+}
+
+/// Messages are encoded as MessagePack, published as base64 strings.
+#[cfg(feature = "msgpack")]
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+  fn encode(&self, value: Value) -> Result<String, serde_json::Error> {
+    encode_base64(&rmp_serde::to_vec_named(&value).map_err(serde_json::Error::custom)?)
+  }
+
+  fn decode(&self, message: &str) -> Result<Value, serde_json::Error> {
+    let bytes = decode_base64(message)?;
+    serde_transcode::transcode(
+      &mut rmp_serde::Deserializer::new(&bytes[..]),
+      serde_json::value::Serializer,
+    )
+  }
+}
+
+// The bytes of a serialized byte buffer, which serde_json represents as an array of numbers.
+fn bytes(value: Value) -> Result<Vec<u8>, serde_json::Error> {
+  serde_json::from_value::<Vec<u8>>(value).map_err(|e| serde_json::Error::custom(format!("expected bytes: {}", e)))
+}
+
+// Binary payloads travel as a JSON string.
+fn encode_base64(bytes: &[u8]) -> Result<String, serde_json::Error> {
+  serde_json::to_string(&base64::encode(bytes))
+}
+
+fn decode_base64(message: &str) -> Result<Vec<u8>, serde_json::Error> {
+  let encoded = serde_json::from_str::<String>(message)?;
+  base64::decode(&encoded).map_err(serde_json::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn passes_raw_strings_through() {
+    assert_eq!(RawString.encode(json!(r#"{"a":1}"#)).unwrap(), r#"{"a":1}"#);
+    assert_eq!(RawString.decode(r#"{"a":1}"#).unwrap(), json!(r#"{"a":1}"#));
+    assert!(RawString.encode(json!({"a": 1})).is_err());
+    assert!(RawString.encode(json!("not JSON")).is_err());
+    assert!(RawString.encode(json!("a\0b")).is_err());
+  }
+
+  #[test]
+  fn wraps_bytes_in_base64() {
+    let encoded = Base64
+      .encode(serde_json::to_value(vec![0u8, 1, 254, 255]).unwrap())
+      .unwrap();
+    assert_eq!(encoded, r#""AAH+/w==""#);
+    assert_eq!(
+      serde_json::from_value::<Vec<u8>>(Base64.decode(&encoded).unwrap()).unwrap(),
+      vec![0, 1, 254, 255]
+    );
+    assert!(Base64.encode(json!([256])).is_err());
+    assert!(Base64.decode(r#""not base64!""#).is_err());
+  }
+}
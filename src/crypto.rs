@@ -0,0 +1,101 @@
+use aes::Aes256;
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Cbc};
+use sha2::{Digest, Sha256};
+
+use crate::ClientError;
+
+// The IV every other PubNub SDK uses, unless told to use a random one.
+const IV: &[u8; 16] = b"0123456789012345";
+
+// Encrypts messages the way the other PubNub SDKs do with a cipher key: AES-256-CBC, keyed with the first 32 hex digits
+// of the key's SHA-256, and published as a base64 JSON string.
+#[derive(Clone)]
+pub(crate) struct Cipher {
+  key: [u8; 32],
+}
+
+impl Cipher {
+  pub(crate) fn new(cipher_key: &str) -> Self {
+    let hash = format!("{:x}", Sha256::digest(cipher_key.as_bytes()));
+    let mut key = [0; 32];
+    key.copy_from_slice(&hash.as_bytes()[..32]);
+    Self { key }
+  }
+
+  fn cbc(&self) -> Cbc<Aes256, Pkcs7> {
+    Cbc::new_from_slices(&self.key, IV).expect("the key and IV are the right length")
+  }
+
+  // Takes the JSON of a message, and returns the JSON to publish in its place.
+  pub(crate) fn encrypt(&self, message: &str) -> String {
+    let encrypted = self.cbc().encrypt_vec(message.as_bytes());
+    serde_json::to_string(&base64::encode(encrypted)).expect("strings serialize")
+  }
+
+  // The reverse of `encrypt`.
+  pub(crate) fn decrypt(&self, message: &str) -> Result<String, ClientError> {
+    let error = || ClientError::Decrypt {
+      raw: message.to_owned(),
+    };
+    let encoded = serde_json::from_str::<String>(message).map_err(|_| error())?;
+    let encrypted = base64::decode(&encoded).map_err(|_| error())?;
+    let decrypted = self.cbc().decrypt_vec(&encrypted).map_err(|_| error())?;
+    String::from_utf8(decrypted).map_err(|_| error())
+  }
+}
+
+// Not derived, so that the key can't end up in a log.
+impl std::fmt::Debug for Cipher {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str("Cipher")
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Client, ClientConfig};
+
+  // Ciphertexts produced by the other PubNub SDKs.
+  const VECTORS: &[(&str, &str)] = &[
+    (
+      r#""Pubnub Messaging API 1""#,
+      r#""f42pIQcWZ9zbTbH8cyLwByD/GsviOE0vcREIEVPARR0=""#,
+    ),
+    (r#""yay!""#, r#""Wi24KS4pcTzvyuGOHubiXg==""#),
+    (
+      r#"{"foo":{"bar":"foobar"}}"#,
+      r#""GsvkCYZoYylL5a7/DKhysDjNbwn+BtBtHj2CvzC4Y4g=""#,
+    ),
+  ];
+
+  #[test]
+  fn matches_other_sdks() {
+    let cipher = Cipher::new("enigma");
+    for &(plain, encrypted) in VECTORS {
+      assert_eq!(cipher.encrypt(plain), encrypted);
+      assert_eq!(cipher.decrypt(encrypted).unwrap(), plain);
+    }
+  }
+
+  #[test]
+  fn rejects_what_it_cannot_decrypt() {
+    let cipher = Cipher::new("enigma");
+    assert!(cipher.decrypt(r#""not base64!""#).is_err());
+    assert!(cipher.decrypt(r#"{"not":"encrypted"}"#).is_err());
+    assert!(Cipher::new("other").decrypt(VECTORS[0].1).is_err());
+  }
+
+  #[test]
+  fn rejects_an_empty_key() {
+    let config = ClientConfig {
+      cipher_key: Some(String::new()),
+      ..Default::default()
+    };
+    assert!(matches!(
+      Client::try_new(config),
+      Err(ClientError::InvalidOptions { .. })
+    ));
+  }
+}
@@ -0,0 +1,348 @@
+use serde::de::{DeserializeOwned, Error as _};
+
+use zugzug_sys::callback::*;
+
+use crate::crypto::Cipher;
+use crate::presence::is_presence_channel;
+use crate::{
+  as_ptr_or_null, decode_message, join_names, Client, ClientError, Codec, Json, JsonError, SubscribeOptions,
+  SubscribeTarget, SubscribeUserData, Subscription,
+};
+
+/// A message along with the metadata PubNub delivered it with: where it came from, when, and who sent it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope<T> {
+  /// The channel the message was published to.
+  pub channel: String,
+  /// The channel group or wildcard subscription that matched `channel`, or `None` if the channel was subscribed to
+  /// directly.
+  pub subscription: Option<String>,
+  /// The timetoken PubNub assigned the message when it was published.
+  pub timetoken: String,
+  /// The UUID of the client that published the message, if PubNub reported one.
+  pub publisher: Option<String>,
+  /// The `meta` object the publisher attached to the message, if any.  `None` as well if it could not be parsed, in
+  /// which case the subscription yields the parse error right after this message.
+  pub meta: Option<serde_json::Value>,
+  pub message_type: MessageType,
+  pub payload: T,
+}
+
+/// How a message was sent.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub enum MessageType {
+  /// With `Client::publish` or `Client::fire`.
+  Published,
+  /// With `Client::signal`.  Signals are never encrypted.
+  Signal,
+}
+
+// A message of a v2 subscribe response, as c-core hands it back.
+struct RawMessage<'a> {
+  channel: String,
+  subscription: Option<String>,
+  timetoken: String,
+  publisher: Option<String>,
+  meta: Option<&'a [u8]>,
+  payload: &'a [u8],
+  message_type: MessageType,
+}
+
+impl<'a> RawMessage<'a> {
+  // Borrows from c-core's reply buffer, so must be used up before the next `pubnub_get_v2`.
+  unsafe fn read(msg: &pubnub_v2_message) -> Self {
+    Self {
+      channel: mem_block_string(&msg.channel).unwrap_or_default(),
+      subscription: mem_block_string(&msg.match_or_group),
+      timetoken: mem_block_string(&msg.tt).unwrap_or_default(),
+      publisher: mem_block_string(&msg.publisher),
+      meta: mem_block(&msg.metadata),
+      payload: mem_block(&msg.payload).unwrap_or_default(),
+      message_type: if msg.message_type == pubnub_message_type_pbsbSignal {
+        MessageType::Signal
+      } else {
+        MessageType::Published
+      },
+    }
+  }
+}
+
+// c-core hands back v2 message fields as non-nul-terminated slices of its reply buffer.
+unsafe fn mem_block<'a>(block: &pubnub_char_mem_block) -> Option<&'a [u8]> {
+  if block.ptr.is_null() || block.size == 0 {
+    None
+  } else {
+    Some(std::slice::from_raw_parts(block.ptr as *const u8, block.size))
+  }
+}
+
+// For the fields PubNub fills in itself (channel names, timetokens, UUIDs), which we need not be strict about.
+unsafe fn mem_block_string(block: &pubnub_char_mem_block) -> Option<String> {
+  mem_block(block).map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn utf8(bytes: &[u8]) -> Result<&str, ClientError> {
+  std::str::from_utf8(bytes).map_err(|e| {
+    let err = serde_json::Error::custom(format!("invalid UTF-8: {}", e));
+    ClientError::ParseError(JsonError::new(err, &String::from_utf8_lossy(bytes)))
+  })
+}
+
+fn parse_meta(meta: &[u8]) -> Result<serde_json::Value, ClientError> {
+  let meta = utf8(meta)?;
+  serde_json::from_str(meta).map_err(|e| ClientError::ParseError(JsonError::new(e, meta)))
+}
+
+// Decodes a message and hands it to `send`, followed by the error if its `meta` could not be parsed.
+fn open<T: DeserializeOwned>(
+  raw: RawMessage,
+  codec: &dyn Codec,
+  cipher: Option<&Cipher>,
+  mut send: impl FnMut(Result<Envelope<T>, ClientError>),
+) {
+  let RawMessage {
+    channel,
+    subscription,
+    timetoken,
+    publisher,
+    meta,
+    payload,
+    message_type,
+  } = raw;
+  let arrived = |e: ClientError| e.arrived(Some(channel.clone()), Some(timetoken.clone()));
+  let (codec, cipher) = if is_presence_channel(&channel) {
+    (&Json as &dyn Codec, None)
+  } else {
+    match message_type {
+      MessageType::Published => (codec, cipher),
+      MessageType::Signal => (codec, None),
+    }
+  };
+
+  let payload = match utf8(payload).and_then(|payload| decode_message::<T>(codec, cipher, payload)) {
+    Ok(payload) => payload,
+    Err(e) => return send(Err(arrived(e))),
+  };
+  // PubNub never encrypts `meta`, so only the payload goes through the codec and cipher.
+  let (meta, meta_error) = match meta.map(parse_meta).transpose() {
+    Ok(meta) => (meta, None),
+    Err(e) => (None, Some(arrived(e))),
+  };
+
+  send(Ok(Envelope {
+    channel,
+    subscription,
+    timetoken,
+    publisher,
+    meta,
+    message_type,
+    payload,
+  }));
+  if let Some(e) = meta_error {
+    send(Err(e));
+  }
+}
+
+pub(crate) unsafe fn subscribe_v2(pb: *mut pubnub_t, target: &SubscribeTarget) -> pubnub_res {
+  let mut options = pubnub_subscribe_v2_defopts();
+  options.channel_group = as_ptr_or_null(&target.group);
+  if let Some(heartbeat) = target.heartbeat {
+    options.heartbeat = heartbeat;
+  }
+  pubnub_subscribe_v2(pb, as_ptr_or_null(&target.channel), options)
+}
+
+unsafe extern "C" fn envelope_callback<T: DeserializeOwned>(
+  pb: *mut pubnub_t,
+  trans: pubnub_trans,
+  result: pubnub_res,
+  user_data: *mut ::std::os::raw::c_void,
+) {
+  let ud: &mut SubscribeUserData<Envelope<T>> = &mut *(user_data as *mut SubscribeUserData<Envelope<T>>);
+  if trans == pubnub_trans_PBTT_SUBSCRIBE_V2 && result == pubnub_res_PNR_OK {
+    let codec = ud.codec.clone();
+    let cipher = ud.cipher.clone();
+    read_messages(pb, |raw| open(raw, &*codec, cipher.as_ref(), |res| ud.send(res)));
+    ud.save_timetoken(pb);
+  }
+
+  ud.next(pb, trans, result);
+}
+
+// For `Client::subscribe`, which yields only the payloads.
+pub(crate) unsafe extern "C" fn payload_callback<T: DeserializeOwned>(
+  pb: *mut pubnub_t,
+  trans: pubnub_trans,
+  result: pubnub_res,
+  user_data: *mut ::std::os::raw::c_void,
+) {
+  let ud: &mut SubscribeUserData<T> = &mut *(user_data as *mut SubscribeUserData<T>);
+  if trans == pubnub_trans_PBTT_SUBSCRIBE_V2 && result == pubnub_res_PNR_OK {
+    let codec = ud.codec.clone();
+    let cipher = ud.cipher.clone();
+    read_messages(pb, |raw| {
+      // The subscriber never sees `meta`, so it can't be bothered with errors parsing it.
+      let raw = RawMessage { meta: None, ..raw };
+      open(raw, &*codec, cipher.as_ref(), |res| {
+        ud.send(res.map(|envelope| envelope.payload))
+      })
+    });
+    ud.save_timetoken(pb);
+  }
+
+  ud.next(pb, trans, result);
+}
+
+// A single response may carry several messages, or none at all (as it does on the first call).
+unsafe fn read_messages(pb: *mut pubnub_t, mut read: impl FnMut(RawMessage)) {
+  loop {
+    let msg = pubnub_get_v2(pb);
+    if msg.payload.ptr.is_null() {
+      break;
+    }
+    read(RawMessage::read(&msg));
+  }
+}
+
+impl Client {
+  /// Subscribes to any number of channels and channel groups at once.  Each message is wrapped in an `Envelope`
+  /// recording the channel it arrived on, its timetoken, its publisher, its `meta` and whether it was a signal.  Use
+  /// `subscribe` if only the payload is needed.
+  pub fn subscribe_channels<T: DeserializeOwned + Send + Sync>(
+    &self,
+    channels: &[&str],
+    groups: &[&str],
+  ) -> Subscription<Envelope<T>> {
+    self.subscribe_channels_with(channels, groups, SubscribeOptions::default())
+  }
+
+  /// Like `subscribe_channels`, but with `SubscribeOptions`.
+  pub fn subscribe_channels_with<T: DeserializeOwned + Send + Sync>(
+    &self,
+    channels: &[&str],
+    groups: &[&str],
+    options: SubscribeOptions,
+  ) -> Subscription<Envelope<T>> {
+    Subscription::new(
+      self,
+      join_names(channels),
+      join_names(groups),
+      options,
+      envelope_callback::<T>,
+      subscribe_v2,
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Base64, PresenceEvent};
+  use serde_json::json;
+
+  fn raw<'a>(channel: &str, meta: Option<&'a [u8]>, payload: &'a [u8]) -> RawMessage<'a> {
+    RawMessage {
+      channel: channel.to_owned(),
+      subscription: None,
+      timetoken: "15000000000000001".to_owned(),
+      publisher: None,
+      meta,
+      payload,
+      message_type: MessageType::Published,
+    }
+  }
+
+  fn open_all(raw: RawMessage) -> Vec<Result<Envelope<serde_json::Value>, ClientError>> {
+    let mut results = Vec::new();
+    open(raw, &Json, None, |res| results.push(res));
+    results
+  }
+
+  #[test]
+  fn reports_invalid_utf8() {
+    let results = open_all(raw("a", None, b"\"\xff\""));
+
+    match results.as_slice() {
+      [Err(ClientError::ParseError(e))] => assert_eq!(e.channel(), Some("a")),
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn keeps_the_payload_when_meta_is_malformed() {
+    let results = open_all(raw("a", Some(b"{oops"), b"{\"x\":1}"));
+
+    match results.as_slice() {
+      [Ok(envelope), Err(ClientError::ParseError(e))] => {
+        assert_eq!(envelope.payload, json!({"x": 1}));
+        assert_eq!(envelope.meta, None);
+        assert_eq!(e.raw(), Some("{oops"));
+      }
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn never_decrypts_signals() {
+    let signal = RawMessage {
+      message_type: MessageType::Signal,
+      ..raw("a", None, b"{\"typing\":true}")
+    };
+    let mut results = Vec::new();
+    open::<serde_json::Value>(signal, &Json, Some(&Cipher::new("enigma")), |res| results.push(res));
+
+    match results.as_slice() {
+      [Ok(envelope)] => {
+        assert_eq!(envelope.payload, json!({"typing": true}));
+        assert_eq!(envelope.message_type, MessageType::Signal);
+      }
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn decodes_presence_events_as_plain_json() {
+    let join = br#"{"action":"join","uuid":"a","occupancy":1,"timestamp":1345546797}"#;
+    let mut results = Vec::new();
+    open(
+      raw("room-pnpres", None, join),
+      &Base64,
+      Some(&Cipher::new("enigma")),
+      |res| results.push(res),
+    );
+
+    match results.as_slice() {
+      [Ok(Envelope {
+        payload: PresenceEvent::Join { uuid, .. },
+        ..
+      })] => assert_eq!(uuid, "a"),
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn reports_where_each_message_of_a_batch_arrived() {
+    let batch = vec![
+      raw("a", None, b"1"),
+      RawMessage {
+        timetoken: "15000000000000002".to_owned(),
+        ..raw("b", None, b"{oops")
+      },
+      RawMessage {
+        timetoken: "15000000000000003".to_owned(),
+        ..raw("c", None, b"3")
+      },
+    ];
+    let results = batch.into_iter().flat_map(open_all).collect::<Vec<_>>();
+
+    match results.as_slice() {
+      [Ok(first), Err(ClientError::ParseError(e)), Ok(last)] => {
+        assert_eq!((first.channel.as_str(), &first.payload), ("a", &json!(1)));
+        assert_eq!(e.channel(), Some("b"));
+        assert_eq!(e.timetoken(), Some("15000000000000002"));
+        assert_eq!((last.channel.as_str(), &last.payload), ("c", &json!(3)));
+      }
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+}
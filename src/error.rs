@@ -0,0 +1,120 @@
+use zugzug_sys::callback::*;
+
+// Maps each c-core result to a variant and a message, in both directions.
+macro_rules! error_kinds {
+  ($($code:ident => $kind:ident: $message:expr,)*) => {
+    /// The outcome of a PubNub transaction, as reported by c-core.
+    #[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Hash)]
+    pub enum PubNubErrorKind {
+      $($kind,)*
+      /// A result this version of the library does not know about.
+      Other(pubnub_res),
+    }
+
+    impl From<pubnub_res> for PubNubErrorKind {
+      #[allow(non_upper_case_globals)]
+      fn from(code: pubnub_res) -> Self {
+        match code {
+          $($code => PubNubErrorKind::$kind,)*
+          other => PubNubErrorKind::Other(other),
+        }
+      }
+    }
+
+    impl From<PubNubErrorKind> for pubnub_res {
+      fn from(kind: PubNubErrorKind) -> Self {
+        match kind {
+          $(PubNubErrorKind::$kind => $code,)*
+          PubNubErrorKind::Other(code) => code,
+        }
+      }
+    }
+
+    impl std::fmt::Display for PubNubErrorKind {
+      fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+          $(PubNubErrorKind::$kind => write!(f, $message),)*
+          PubNubErrorKind::Other(code) => write!(f, "unknown result {}", code),
+        }
+      }
+    }
+  };
+}
+
+error_kinds! {
+  pubnub_res_PNR_OK => Ok: "success",
+  pubnub_res_PNR_ADDR_RESOLUTION_FAILED => AddrResolutionFailed: "DNS resolution failed",
+  pubnub_res_PNR_CONNECT_FAILED => ConnectFailed: "unable to connect",
+  pubnub_res_PNR_CONNECTION_TIMEOUT => ConnectionTimeout: "timed out connecting",
+  pubnub_res_PNR_TIMEOUT => Timeout: "timed out waiting for a response",
+  pubnub_res_PNR_ABORTED => Aborted: "connection aborted",
+  pubnub_res_PNR_IO_ERROR => IoError: "communication error",
+  pubnub_res_PNR_HTTP_ERROR => HttpError: "HTTP error",
+  pubnub_res_PNR_FORMAT_ERROR => FormatError: "unexpected response format",
+  pubnub_res_PNR_CANCELLED => Cancelled: "transaction cancelled",
+  pubnub_res_PNR_STARTED => Started: "transaction started",
+  pubnub_res_PNR_IN_PROGRESS => InProgress: "another transaction is in progress",
+  pubnub_res_PNR_RX_BUFF_NOT_EMPTY => RxBuffNotEmpty: "not all of the previous response has been read",
+  pubnub_res_PNR_TX_BUFF_TOO_SMALL => TxBuffTooSmall: "request too long to send",
+  pubnub_res_PNR_INVALID_CHANNEL => InvalidChannel: "invalid channel",
+  pubnub_res_PNR_PUBLISH_FAILED => PublishFailed: "publish failed",
+  pubnub_res_PNR_CHANNEL_REGISTRY_ERROR => ChannelRegistryError: "channel registry error",
+  pubnub_res_PNR_REPLY_TOO_BIG => ReplyTooBig: "response too big",
+  pubnub_res_PNR_INTERNAL_ERROR => InternalError: "internal error",
+  pubnub_res_PNR_CRYPTO_NOT_SUPPORTED => CryptoNotSupported: "encryption not supported",
+  pubnub_res_PNR_BAD_COMPRESSION_FORMAT => BadCompressionFormat: "bad compression format",
+  pubnub_res_PNR_INVALID_PARAMETERS => InvalidParameters: "invalid parameters",
+  pubnub_res_PNR_ERROR_ON_SERVER => ErrorOnServer: "server error",
+  pubnub_res_PNR_AUTHENTICATION_FAILED => AuthenticationFailed: "authentication failed",
+  pubnub_res_PNR_ACCESS_DENIED => AccessDenied: "access denied",
+}
+
+impl PubNubErrorKind {
+  /// Whether the same transaction might succeed if tried again, i.e. the failure was in getting to or hearing back
+  /// from PubNub rather than in the request itself.
+  pub fn is_retryable(self) -> bool {
+    matches!(
+      self,
+      PubNubErrorKind::AddrResolutionFailed
+        | PubNubErrorKind::ConnectFailed
+        | PubNubErrorKind::ConnectionTimeout
+        | PubNubErrorKind::Timeout
+        | PubNubErrorKind::Aborted
+        | PubNubErrorKind::IoError
+        | PubNubErrorKind::ErrorOnServer
+    )
+  }
+
+  /// Whether the auth key was rejected, or lacks permission for the transaction.
+  pub fn is_auth_error(self) -> bool {
+    matches!(
+      self,
+      PubNubErrorKind::AuthenticationFailed | PubNubErrorKind::AccessDenied
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn converts_losslessly() {
+    for code in 0..100 {
+      assert_eq!(pubnub_res::from(PubNubErrorKind::from(code)), code);
+    }
+    assert_eq!(
+      PubNubErrorKind::from(pubnub_res_PNR_ACCESS_DENIED),
+      PubNubErrorKind::AccessDenied
+    );
+    assert_eq!(PubNubErrorKind::from(1000), PubNubErrorKind::Other(1000));
+  }
+
+  #[test]
+  fn classifies() {
+    assert!(PubNubErrorKind::Timeout.is_retryable());
+    assert!(!PubNubErrorKind::AccessDenied.is_retryable());
+    assert!(PubNubErrorKind::AccessDenied.is_auth_error());
+    assert!(!PubNubErrorKind::Cancelled.is_auth_error());
+  }
+}
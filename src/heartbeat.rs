@@ -0,0 +1,180 @@
+use futures::Future;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CString;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use zugzug_sys::callback::*;
+
+use crate::{as_ptr_or_null, ChannelConfig, ClientError, TransactionFuture};
+
+/// How a client keeps itself present on the channels it subscribes to.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Hash)]
+pub struct HeartbeatConfig {
+  /// How long PubNub waits to hear from the client before timing it out of its channels.
+  pub presence_timeout: Duration,
+  /// How often to send a heartbeat.  PubNub recommends a little under half of `presence_timeout`.
+  pub interval: Duration,
+}
+
+impl HeartbeatConfig {
+  pub(crate) fn validate(&self) -> Result<(), ClientError> {
+    let reason = if self.interval == Duration::from_secs(0) {
+      "the heartbeat interval must not be zero"
+    } else if self.interval >= self.presence_timeout {
+      "the heartbeat interval must be shorter than the presence timeout"
+    } else {
+      return Ok(());
+    };
+    Err(ClientError::InvalidOptions { reason })
+  }
+}
+
+// The channels and groups of every live subscription, keyed by an id handed out to each subscription.
+#[derive(Debug, Default)]
+pub(crate) struct SubscribedChannels {
+  next_id: u64,
+  subscriptions: HashMap<u64, (Option<CString>, Option<CString>)>,
+}
+
+impl SubscribedChannels {
+  pub(crate) fn add(&mut self, channel: &Option<CString>, group: &Option<CString>) -> u64 {
+    let id = self.next_id;
+    self.next_id += 1;
+    self.subscriptions.insert(id, (channel.clone(), group.clone()));
+    id
+  }
+
+  pub(crate) fn update(&mut self, id: u64, channel: Option<CString>, group: Option<CString>) {
+    if let Some(entry) = self.subscriptions.get_mut(&id) {
+      *entry = (channel, group);
+    }
+  }
+
+  pub(crate) fn remove(&mut self, id: u64) {
+    self.subscriptions.remove(&id);
+  }
+
+  // Every subscribed channel and group, deduplicated and comma-separated as c-core expects.
+  fn joined(&self) -> (Option<CString>, Option<CString>) {
+    fn join<'a>(lists: impl Iterator<Item = &'a Option<CString>>) -> Option<CString> {
+      let names = lists
+        .flatten()
+        .flat_map(|list| list.to_str().unwrap_or_default().split(','))
+        .filter(|name| !name.is_empty())
+        .collect::<BTreeSet<_>>();
+      crate::join_names(&names.into_iter().collect::<Vec<_>>())
+    }
+
+    (
+      join(self.subscriptions.values().map(|(channel, _)| channel)),
+      join(self.subscriptions.values().map(|(_, group)| group)),
+    )
+  }
+}
+
+// Owned by a `Client` (and its clones).  Dropping it stops the heartbeat thread after any heartbeat in flight.
+#[derive(Debug, Default)]
+pub(crate) struct Heartbeat {
+  presence_timeout: Option<Duration>,
+  subscribed: Arc<Mutex<SubscribedChannels>>,
+  _stop: Option<Sender<()>>,
+}
+
+impl Heartbeat {
+  pub(crate) fn new(config: Option<HeartbeatConfig>, keys: ChannelConfig) -> Self {
+    let subscribed = Arc::new(Mutex::new(SubscribedChannels::default()));
+
+    let stop = config.as_ref().map(|config| {
+      let (tx, rx) = std::sync::mpsc::channel();
+      let interval = config.interval;
+      let subscribed = subscribed.clone();
+      std::thread::Builder::new()
+        .name("zugzug-heartbeat".to_owned())
+        .spawn(move || run(keys, interval, subscribed, rx))
+        .expect("unable to spawn heartbeat thread");
+      tx
+    });
+
+    Self {
+      presence_timeout: config.map(|config| config.presence_timeout),
+      subscribed,
+      _stop: stop,
+    }
+  }
+
+  pub(crate) fn subscribed(&self) -> Arc<Mutex<SubscribedChannels>> {
+    self.subscribed.clone()
+  }
+
+  // The presence timeout to send along with subscribe requests, in seconds.
+  pub(crate) fn presence_timeout(&self) -> Option<u32> {
+    self.presence_timeout.map(|timeout| timeout.as_secs() as u32)
+  }
+}
+
+fn run(keys: ChannelConfig, interval: Duration, subscribed: Arc<Mutex<SubscribedChannels>>, stop: Receiver<()>) {
+  // Nothing is ever sent on `stop`, so this runs until the `Heartbeat` drops the sender.
+  while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+    let (channel, group) = subscribed.lock().unwrap().joined();
+    if channel.is_none() && group.is_none() {
+      continue;
+    }
+
+    let config = ChannelConfig {
+      auth_key: keys.auth_key.clone(),
+      publish_key: keys.publish_key.clone(),
+      subscribe_key: keys.subscribe_key.clone(),
+      client_uuid: keys.client_uuid.clone(),
+      channel,
+      group,
+    };
+    TransactionFuture::new(
+      config,
+      pubnub_trans_PBTT_HEARTBEAT,
+      Box::new(|ctx, config| unsafe {
+        pubnub_heartbeat(ctx, as_ptr_or_null(&config.channel), as_ptr_or_null(&config.group))
+      }),
+      Box::new(|_| Ok(())),
+    )
+    .wait()
+    .map_err(|e| eprintln!("Unable to send heartbeat: {}", e))
+    .ok();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn c(s: &str) -> Option<CString> {
+    Some(CString::new(s).unwrap())
+  }
+
+  #[test]
+  fn joins_subscribed_channels() {
+    let mut subscribed = SubscribedChannels::default();
+    subscribed.add(&c("b,a"), &None);
+    let id = subscribed.add(&c("a,c"), &c("g"));
+
+    assert_eq!(subscribed.joined(), (c("a,b,c"), c("g")));
+
+    subscribed.remove(id);
+
+    assert_eq!(subscribed.joined(), (c("a,b"), None));
+  }
+
+  #[test]
+  fn rejects_intervals_that_would_let_clients_time_out() {
+    let config = |interval| HeartbeatConfig {
+      presence_timeout: Duration::from_secs(60),
+      interval: Duration::from_secs(interval),
+    };
+
+    assert!(config(25).validate().is_ok());
+    assert!(config(0).validate().is_err());
+    assert!(config(60).validate().is_err());
+    assert!(config(90).validate().is_err());
+  }
+}
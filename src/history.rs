@@ -0,0 +1,268 @@
+use futures::stream::Stream;
+use futures::{Async, Future};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::marker::PhantomData;
+
+use zugzug_sys::callback::*;
+
+use crate::crypto::Cipher;
+use crate::{as_ptr_or_null, decode_message, Client, ClientError, Codec, TransactionFuture};
+
+// PubNub will not return more than this many messages from a single history request.
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Options for `Client::history`.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct HistoryOptions {
+  /// Timetoken to start from (exclusive).
+  pub start: Option<String>,
+  /// Timetoken to stop at (inclusive).
+  pub end: Option<String>,
+  /// The maximum number of messages to return across all pages, or `None` for every message in range.
+  pub count: Option<usize>,
+  /// Traverse from oldest to newest rather than from newest to oldest.
+  pub reverse: bool,
+  /// Fill in `HistoryMessage::timetoken`.
+  pub include_timetoken: bool,
+}
+
+/// A message read from a channel's history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryMessage<T> {
+  /// When the message was published, if `HistoryOptions::include_timetoken` was set.
+  pub timetoken: Option<String>,
+  pub message: T,
+}
+
+// The body of a history response: `[[messages...], start, end]`.
+#[derive(Deserialize, Debug, PartialEq)]
+struct HistoryPage(Vec<serde_json::Value>, u64, u64);
+
+#[derive(Deserialize)]
+struct TimetokenMessage {
+  message: serde_json::Value,
+  timetoken: u64,
+}
+
+// c-core splits the top level of the history response, so put it back together before parsing.
+unsafe fn parse_history_page(pb: *mut pubnub_t) -> Result<HistoryPage, ClientError> {
+  let mut parts = Vec::new();
+  loop {
+    let ptr = pubnub_get(pb);
+    if ptr.is_null() {
+      break;
+    }
+    parts.push(std::ffi::CStr::from_ptr(ptr).to_string_lossy().into_owned());
+  }
+  if parts.is_empty() {
+    return Err(ClientError::NoResponse);
+  }
+  serde_json::from_str(&format!("[{}]", parts.join(","))).map_err(ClientError::from)
+}
+
+fn decode<T: DeserializeOwned>(
+  value: serde_json::Value,
+  include_timetoken: bool,
+  codec: &dyn Codec,
+  cipher: Option<&Cipher>,
+) -> Result<HistoryMessage<T>, ClientError> {
+  let (timetoken, message) = if include_timetoken {
+    let TimetokenMessage { message, timetoken } = serde_json::from_value(value)?;
+    (Some(timetoken.to_string()), message)
+  } else {
+    (None, value)
+  };
+
+  Ok(HistoryMessage {
+    timetoken,
+    message: decode_message(codec, cipher, &message.to_string())?,
+  })
+}
+
+/// The messages in a channel's history, fetched a page at a time.
+///
+/// Each page is in chronological order.  Unless `HistoryOptions::reverse` is set, pages are fetched from newest to
+/// oldest.
+pub struct History<T> {
+  client: Client,
+  channel: String,
+  options: HistoryOptions,
+  // How many more messages we may fetch, if limited.
+  remaining: Option<usize>,
+  page: Option<TransactionFuture<HistoryPage>>,
+  buffer: VecDeque<serde_json::Value>,
+  _message: PhantomData<fn() -> T>,
+}
+
+impl<T> History<T> {
+  fn page_size(&self) -> usize {
+    self.remaining.map_or(MAX_PAGE_SIZE, |r| r.min(MAX_PAGE_SIZE))
+  }
+
+  fn fetch_page(&self) -> TransactionFuture<HistoryPage> {
+    let channel_c = CString::new(self.channel.as_str()).expect("UTF-8 doesn't include nul");
+    let start = self
+      .options
+      .start
+      .clone()
+      .map(|s| CString::new(s).expect("UTF-8 doesn't include nul"));
+    let end = self
+      .options
+      .end
+      .clone()
+      .map(|e| CString::new(e).expect("UTF-8 doesn't include nul"));
+    let count = self.page_size() as i32;
+    let reverse = self.options.reverse;
+    let include_token = self.options.include_timetoken;
+
+    TransactionFuture::new(
+      self.client.channel_config(Some(channel_c), None),
+      pubnub_trans_PBTT_HISTORY,
+      Box::new(move |ctx, config| unsafe {
+        let mut opts = pubnub_history_defopts();
+        opts.count = count;
+        opts.reverse = reverse;
+        opts.include_token = include_token;
+        opts.start = as_ptr_or_null(&start);
+        opts.end = as_ptr_or_null(&end);
+        pubnub_history_ex(ctx, as_ptr_or_null(&config.channel), opts)
+      }),
+      Box::new(|pb| unsafe { parse_history_page(pb) }),
+    )
+  }
+
+  // Takes the messages out of `page` and points the options at the following page, if there might be one.
+  fn advance(&mut self, page: HistoryPage) -> bool {
+    let HistoryPage(messages, start, end) = page;
+    let full = messages.len() >= self.page_size();
+    self.remaining = self.remaining.map(|r| r.saturating_sub(messages.len()));
+    self.buffer.extend(messages);
+
+    let next = if self.options.reverse { end } else { start };
+    if full && next != 0 && self.remaining != Some(0) {
+      self.options.start = Some(next.to_string());
+      true
+    } else {
+      false
+    }
+  }
+}
+
+impl<T: DeserializeOwned> Stream for History<T> {
+  type Item = HistoryMessage<T>;
+  type Error = ClientError;
+
+  fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+    loop {
+      if let Some(value) = self.buffer.pop_front() {
+        return decode(
+          value,
+          self.options.include_timetoken,
+          &*self.client.codec,
+          self.client.cipher.as_ref(),
+        )
+        .map(|m| Async::Ready(Some(m)));
+      }
+
+      let page = match self.page {
+        Some(ref mut page) => page.poll(),
+        None => return Ok(Async::Ready(None)),
+      };
+      match page {
+        Ok(Async::Ready(page)) => {
+          self.page = if self.advance(page) {
+            Some(self.fetch_page())
+          } else {
+            None
+          };
+        }
+        Ok(Async::NotReady) => return Ok(Async::NotReady),
+        Err(e) => {
+          self.page = None;
+          return Err(e);
+        }
+      }
+    }
+  }
+}
+
+impl Client {
+  /// Reads the messages stored for `channel`, automatically requesting further pages as the stream is polled.
+  pub fn history<T: DeserializeOwned>(&self, channel: &str, options: HistoryOptions) -> History<T> {
+    let mut history = History {
+      client: self.clone(),
+      channel: channel.to_owned(),
+      remaining: options.count,
+      options,
+      page: None,
+      buffer: VecDeque::new(),
+      _message: PhantomData,
+    };
+    if history.remaining != Some(0) {
+      history.page = Some(history.fetch_page());
+    }
+    history
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn history(options: HistoryOptions) -> History<u32> {
+    History {
+      client: Client::for_test(),
+      channel: "c".to_owned(),
+      remaining: options.count,
+      options,
+      page: None,
+      buffer: VecDeque::new(),
+      _message: PhantomData,
+    }
+  }
+
+  #[test]
+  fn decodes_messages_with_timetokens() {
+    let page: HistoryPage =
+      serde_json::from_str(r#"[[{"message":1,"timetoken":15000000000000001}],15000000000000001,15000000000000001]"#)
+        .unwrap();
+    let HistoryPage(mut messages, _, _) = page;
+
+    assert_eq!(
+      decode::<u32>(messages.remove(0), true, &crate::Json, None).unwrap(),
+      HistoryMessage {
+        timetoken: Some("15000000000000001".to_owned()),
+        message: 1,
+      }
+    );
+  }
+
+  #[test]
+  fn pages_backwards_until_a_short_page() {
+    let mut h = history(HistoryOptions {
+      count: Some(150),
+      ..Default::default()
+    });
+
+    assert!(h.advance(HistoryPage(vec![0.into(); 100], 10, 20)));
+    assert_eq!(h.options.start, Some("10".to_owned()));
+    assert_eq!(h.page_size(), 50);
+    assert!(!h.advance(HistoryPage(vec![0.into(); 50], 5, 9)));
+    assert_eq!(h.buffer.len(), 150);
+  }
+
+  #[test]
+  fn pages_forwards_when_reversed() {
+    let mut h = history(HistoryOptions {
+      reverse: true,
+      ..Default::default()
+    });
+
+    assert!(h.advance(HistoryPage(vec![0.into(); 100], 10, 20)));
+    assert_eq!(h.options.start, Some("20".to_owned()));
+    assert!(!h.advance(HistoryPage(vec![0.into(); 3], 21, 23)));
+  }
+}
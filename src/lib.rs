@@ -2,48 +2,388 @@ use futures::stream::Stream;
 use futures::sync::mpsc::{Receiver, Sender};
 use futures::task::Task;
 use futures::{Async, Future};
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::ffi::CString;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 use zugzug_sys::{callback::*, dns::*};
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Hash)]
+use crypto::Cipher;
+use heartbeat::{Heartbeat, SubscribedChannels};
+use pool::ContextPool;
+use queue::{QueueReceiver, QueueSender};
+use reconnect::ReconnectTimer;
+
+mod channel_group;
+mod codec;
+mod crypto;
+mod envelope;
+mod error;
+mod heartbeat;
+mod history;
+mod pool;
+mod presence;
+mod publish;
+mod queue;
+mod reconnect;
+#[cfg(feature = "std-future")]
+mod std_future;
+
+pub use channel_group::*;
+pub use codec::*;
+pub use envelope::*;
+pub use error::PubNubErrorKind;
+pub use heartbeat::HeartbeatConfig;
+pub use history::*;
+pub use pool::{PoolConfig, PoolStats, WhenExhausted};
+pub use presence::*;
+pub use publish::{PublishMethod, PublishOptions};
+pub use queue::OverflowPolicy;
+pub use reconnect::{ConnectionStatus, ConnectionStatusStream, ReconnectPolicy};
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Hash, Default)]
 pub struct ClientConfig {
   pub auth_key: String,
   pub publish_key: String,
   pub subscribe_key: String,
   pub client_uuid: String,
+  /// Keep this client present on the channels it subscribes to by sending heartbeats from a background thread.
+  pub heartbeat: Option<HeartbeatConfig>,
+  /// Reuse a pool of contexts for publishes, rather than connecting to PubNub afresh for every message.
+  pub publish_pool: Option<PoolConfig>,
+  /// Encrypt published messages with this key, and decrypt received and stored ones, as the other PubNub SDKs do.
+  pub cipher_key: Option<String>,
+}
+
+/// Options for `Client::subscribe_with` and `Client::subscribe_channels_with`.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct SubscribeOptions {
+  /// Start from this timetoken (e.g. one saved from `Subscription::timetoken`) rather than from "now", so that
+  /// messages published since then are delivered too.
+  pub timetoken: Option<String>,
+  /// What to do when a long-poll fails.  Connection status changes can be watched with
+  /// `Subscription::connection_status`.
+  pub reconnect: ReconnectPolicy,
+  /// How many messages to buffer for the stream.  Defaults to 10.
+  pub capacity: usize,
+  /// What to do with messages that arrive while the buffer is full.  Defaults to `OverflowPolicy::DropNewest`.
+  pub overflow: OverflowPolicy,
+}
+
+impl Default for SubscribeOptions {
+  fn default() -> Self {
+    Self {
+      timetoken: None,
+      reconnect: ReconnectPolicy::default(),
+      capacity: 10,
+      overflow: OverflowPolicy::DropNewest,
+    }
+  }
 }
 
+#[derive(Clone, Debug)]
 struct ChannelConfig {
   auth_key: CString,
   publish_key: CString,
   subscribe_key: CString,
   client_uuid: CString,
-  channel: CString,
-  group: CString,
+  channel: Option<CString>,
+  group: Option<CString>,
+}
+
+// How long tearing down a subscription or transaction may wait on PubNub before giving up and leaking the context.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum SubscribeState {
+  // A long-poll is in flight, and the callback will start another when it completes.
+  Polling,
+  // Asked to stop; the callback will not start another long-poll.
+  Stopping,
+  // No transaction is in flight, so the context may be reused or freed.
+  Idle,
+  // A long-poll failed, and another is scheduled on the client's reconnect timer.  No transaction is in flight.
+  Waiting,
+  // A leave is in flight.
+  Leaving,
+  Left(pubnub_res),
+}
+
+// Shared between a `Subscription` and its callback so that the context can be torn down without pulling the user data
+// out from under a callback that is still running.
+struct SubscribeStatus {
+  state: Mutex<SubscribeState>,
+  changed: Condvar,
+}
+
+impl SubscribeStatus {
+  fn set(&self, state: SubscribeState) {
+    *self.state.lock().unwrap() = state;
+    self.changed.notify_all();
+  }
+
+  // Returns the first state not matching `condition`, or the current state if that takes longer than `LEAVE_TIMEOUT`.
+  fn wait_while(&self, condition: impl Fn(SubscribeState) -> bool) -> SubscribeState {
+    let state = self.state.lock().unwrap();
+    let (state, _) = self
+      .changed
+      .wait_timeout_while(state, LEAVE_TIMEOUT, |s| condition(*s))
+      .unwrap();
+    *state
+  }
+}
+
+// What a subscribe context long-polls on.  Shared with the `Subscription`, which may change it between long-polls.
+struct SubscribeTarget {
+  channel: Option<CString>,
+  group: Option<CString>,
+  // The presence timeout to ask for, in seconds.
+  heartbeat: Option<u32>,
+}
+
+impl SubscribeTarget {
+  fn is_empty(&self) -> bool {
+    self.channel.is_none() && self.group.is_none()
+  }
 }
 
 struct SubscribeUserData<T> {
-  channel: CString,
-  tx: Sender<Result<T, ClientError>>,
+  target: Arc<Mutex<SubscribeTarget>>,
+  subscribe: SubscribeFn,
+  status: Arc<SubscribeStatus>,
+  // The timetoken of the last long-poll whose messages have all been sent to `tx`.
+  timetoken: Arc<Mutex<Option<String>>>,
+  tx: QueueSender<Result<T, ClientError>>,
+  status_tx: Sender<ConnectionStatus>,
+  reconnect: ReconnectPolicy,
+  // Failures since the last successful long-poll.
+  attempts: u32,
+  connected: bool,
+  dead_letter: Arc<Mutex<Option<DeadLetterFn>>>,
+  codec: Arc<dyn Codec>,
+  cipher: Option<Cipher>,
+  timer: Arc<ReconnectTimer>,
+}
+
+// Receives the messages of a subscription that could not be decoded.
+type DeadLetterFn = Box<dyn FnMut(JsonError) + Send>;
+
+// Lets a raw pointer be moved to the thread that reconnects.
+struct SendPtr<P>(P);
+
+unsafe impl<P> Send for SendPtr<P> {}
+
+impl<T> SubscribeUserData<T> {
+  fn send(&mut self, res: Result<T, ClientError>) {
+    let res = match res {
+      Err(ClientError::ParseError(e)) => match *self.dead_letter.lock().unwrap() {
+        Some(ref mut dead_letter) => return dead_letter(e),
+        None => Err(ClientError::ParseError(e)),
+      },
+      res => res,
+    };
+    self.tx.send(res);
+  }
+
+  // Must only be called once every message of the long-poll has been sent.
+  unsafe fn save_timetoken(&self, pb: *mut pubnub_t) {
+    let ptr = pubnub_last_time_token(pb);
+    if !ptr.is_null() {
+      let tt = std::ffi::CStr::from_ptr(ptr).to_string_lossy().into_owned();
+      *self.timetoken.lock().unwrap() = Some(tt);
+    }
+  }
+
+  fn send_status(&mut self, status: ConnectionStatus) {
+    // Nobody may be watching, so a full channel is not worth reporting.
+    self.status_tx.try_send(status).ok();
+  }
+
+  // Called at the end of every callback.  Restarts the long-poll, unless the subscription is being torn down.
+  unsafe fn next(&mut self, pb: *mut pubnub_t, trans: pubnub_trans, result: pubnub_res) {
+    let status = self.status.clone();
+    let mut state = status.state.lock().unwrap();
+    if trans == pubnub_trans_PBTT_LEAVE {
+      *state = SubscribeState::Left(result);
+    } else if *state == SubscribeState::Polling {
+      if result == pubnub_res_PNR_OK {
+        self.attempts = 0;
+        if !self.connected {
+          self.connected = true;
+          self.send_status(ConnectionStatus::Connected);
+        }
+        self.restart(pb, &mut state);
+      } else if result == pubnub_res_PNR_CANCELLED {
+        // We only ever cancel a long-poll ourselves (e.g. to change channels), so that is not worth reporting.
+        self.restart(pb, &mut state);
+      } else {
+        self.failed(pb, result, &mut state);
+      }
+    } else {
+      *state = SubscribeState::Idle;
+    }
+    status.changed.notify_all();
+  }
+
+  // Starts the next long-poll.  Must only be called with the state locked and no transaction in flight.
+  unsafe fn restart(&mut self, pb: *mut pubnub_t, state: &mut SubscribeState) {
+    let target = self.target.lock().unwrap();
+    if target.is_empty() {
+      // Everything was removed from the subscription, so wait for something to be added back.
+      *state = SubscribeState::Idle;
+      return;
+    }
+    // TODO: verify that we are happy with this here.  PubNub docs suggest that it is ok to do operations like this inside of a callback, but not recommended (as it can make debugging harder).  Our use case is simple (a loop), so maybe we're ok?
+    let result = (self.subscribe)(pb, &target);
+    drop(target);
+    if result == pubnub_res_PNR_STARTED {
+      *state = SubscribeState::Polling;
+    } else {
+      self.failed(pb, result, state);
+    }
+  }
+
+  // Schedules another long-poll, or gives up, as the reconnect policy says.
+  unsafe fn failed(&mut self, pb: *mut pubnub_t, result: pubnub_res, state: &mut SubscribeState) {
+    let error = PubNubErrorKind::from(result);
+    self.connected = false;
+    self.attempts += 1;
+    match self.reconnect.delay(self.attempts) {
+      Some(delay) => {
+        *state = SubscribeState::Waiting;
+        self.send_status(ConnectionStatus::Reconnecting {
+          attempt: self.attempts,
+          delay,
+          error,
+        });
+        // We must not wait here, as that would hold up c-core's callback thread.
+        let status = self.status.clone();
+        let ud = SendPtr(self as *mut Self as *mut std::ffi::c_void);
+        let pb = SendPtr(pb);
+        let restart: unsafe fn(*mut std::ffi::c_void, *mut pubnub_t, &mut SubscribeState) = restart_user_data::<T>;
+        self.timer.schedule(delay, move || {
+          let mut state = status.state.lock().unwrap();
+          // If the state changed the subscription is being torn down, and the user data may already be gone.
+          if *state == SubscribeState::Waiting {
+            unsafe { restart(ud.0, pb.0, &mut state) };
+            status.changed.notify_all();
+          }
+        });
+      }
+      None => {
+        *state = SubscribeState::Idle;
+        self.send(Err(ClientError::PubNub { kind: error }));
+        self.send_status(ConnectionStatus::Disconnected { error });
+      }
+    }
+  }
+}
+
+// Type-erased so that the reconnect job does not need `T: 'static`.
+unsafe fn restart_user_data<T>(user_data: *mut std::ffi::c_void, pb: *mut pubnub_t, state: &mut SubscribeState) {
+  (*(user_data as *mut SubscribeUserData<T>)).restart(pb, state);
+}
+
+unsafe fn drop_user_data<T>(user_data: *mut std::ffi::c_void) {
+  drop(Box::from_raw(user_data as *mut SubscribeUserData<T>));
+}
+
+// Starts (or restarts) the long-poll on a subscribe context.
+type SubscribeFn = unsafe fn(*mut pubnub_t, &SubscribeTarget) -> pubnub_res;
+type SubscribeCallback = unsafe extern "C" fn(*mut pubnub_t, pubnub_trans, pubnub_res, *mut ::std::os::raw::c_void);
+
+// c-core takes lists of channels or groups as a single comma-separated string.
+fn join_names(names: &[&str]) -> Option<CString> {
+  if names.is_empty() {
+    None
+  } else {
+    Some(CString::new(names.join(",")).expect("UTF-8 doesn't include nul"))
+  }
+}
+
+// Adds and removes names from a comma-separated list, keeping the order of the names that remain.
+fn edit_names(list: &Option<CString>, add: &[&str], remove: &[&str]) -> Option<CString> {
+  let mut names = list
+    .as_ref()
+    .map_or("", |list| list.to_str().unwrap_or_default())
+    .split(',')
+    .filter(|name| !name.is_empty() && !remove.contains(name))
+    .collect::<Vec<_>>();
+  for name in add {
+    if !names.contains(name) {
+      names.push(name);
+    }
+  }
+  join_names(&names)
+}
+
+// The names in a comma-separated list that are also in `names`, e.g. those that removing `names` would remove.
+fn kept_names(list: &Option<CString>, names: &[&str]) -> Option<CString> {
+  let kept = list
+    .as_ref()
+    .map_or("", |list| list.to_str().unwrap_or_default())
+    .split(',')
+    .filter(|name| names.contains(name))
+    .collect::<Vec<_>>();
+  join_names(&kept)
 }
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Hash)]
+unsafe fn owned_string(ptr: *const std::os::raw::c_char) -> Option<String> {
+  if ptr.is_null() {
+    None
+  } else {
+    Some(std::ffi::CStr::from_ptr(ptr).to_string_lossy().into_owned())
+  }
+}
+
+// c-core treats a null channel or group as "not present", so an absent value maps to a null pointer.
+fn as_ptr_or_null(s: &Option<CString>) -> *const std::os::raw::c_char {
+  s.as_ref().map_or(std::ptr::null(), |s| s.as_ptr())
+}
+
+/// Clients are compared by their keys and UUID.
+#[derive(Clone, Debug)]
 pub struct Client {
   auth_key: CString,
   publish_key: CString,
   subscribe_key: CString,
   client_uuid: CString,
+  heartbeat: Arc<Heartbeat>,
+  publish_pool: Option<Arc<ContextPool>>,
+  codec: Arc<dyn Codec>,
+  cipher: Option<Cipher>,
+  reconnect_timer: Arc<ReconnectTimer>,
 }
 
 impl Client {
+  /// Panics if `config` is invalid, e.g. its heartbeat interval is not shorter than its presence timeout.  Use
+  /// `try_new` to handle that instead.
   pub fn new(config: ClientConfig) -> Self {
+    Self::try_new(config).unwrap_or_else(|e| panic!("Invalid client config: {}", e))
+  }
+
+  pub fn try_new(config: ClientConfig) -> Result<Self, ClientError> {
+    if let Some(ref heartbeat) = config.heartbeat {
+      heartbeat.validate()?;
+    }
+    if let Some(ref publish_pool) = config.publish_pool {
+      publish_pool.validate()?;
+    }
+    if config.cipher_key.as_deref() == Some("") {
+      return Err(ClientError::InvalidOptions {
+        reason: "a cipher key must not be empty",
+      });
+    }
     let ClientConfig {
       auth_key,
       publish_key,
       subscribe_key,
       client_uuid,
+      heartbeat,
+      publish_pool,
+      cipher_key,
     } = config;
 
     let auth_key = CString::new(auth_key).expect("UTF-8 doesn't include nul");
@@ -73,60 +413,329 @@ impl Client {
         }
     }
 
-    Self {
+    let keys = ChannelConfig {
+      auth_key: auth_key.clone(),
+      publish_key: publish_key.clone(),
+      subscribe_key: subscribe_key.clone(),
+      client_uuid: client_uuid.clone(),
+      channel: None,
+      group: None,
+    };
+    let publish_pool = publish_pool.map(|config| Arc::new(ContextPool::new(config, keys.clone())));
+    let heartbeat = Arc::new(Heartbeat::new(heartbeat, keys));
+
+    Ok(Self {
       auth_key,
       publish_key,
       subscribe_key,
       client_uuid,
+      heartbeat,
+      publish_pool,
+      codec: Arc::new(Json),
+      cipher: cipher_key.as_deref().map(Cipher::new),
+      reconnect_timer: Arc::default(),
+    })
+  }
+
+  /// A client that encodes and decodes messages with `codec` instead, sharing everything else with this one.  Use it
+  /// for a single subscription or publish, or keep it in place of this client.
+  pub fn with_codec(&self, codec: impl Codec + 'static) -> Self {
+    Self {
+      codec: Arc::new(codec),
+      ..self.clone()
     }
   }
 
-  pub fn subscribe<'a, T: Send + Sync + Deserialize<'a>>(&self, channel: &str, group: &str) -> Subscription<T> {
-    let channel_c = CString::new(channel).expect("UTF-8 doesn't include nul");
-    let group_c = CString::new(group).expect("UTF-8 doesn't include nul");
+  /// Subscribes to a channel, a channel group, or both.  At least one of `channel` or `group` must be given, otherwise
+  /// the returned `Subscription` yields a `ClientError::PubNub` error.  Signals are yielded like any other message,
+  /// though they are never decrypted; use `subscribe_channels` to tell them apart.
+  ///
+  /// c-core reuses its reply buffer for the next long-poll, so messages must not borrow from it:
+  ///
+  /// ```compile_fail
+  /// # use serde::Deserialize;
+  /// # use zugzug::{Client, Subscription};
+  /// #[derive(Deserialize)]
+  /// struct Borrowed<'a> {
+  ///   name: &'a str,
+  /// }
+  ///
+  /// fn subscribe(client: &Client) -> Subscription<Borrowed<'static>> {
+  ///   client.subscribe(Some("channel"), None)
+  /// }
+  /// ```
+  pub fn subscribe<T: DeserializeOwned + Send + Sync>(
+    &self,
+    channel: Option<&str>,
+    group: Option<&str>,
+  ) -> Subscription<T> {
+    self.subscribe_with(channel, group, SubscribeOptions::default())
+  }
 
-    let config = ChannelConfig {
-      auth_key: self.auth_key.clone(),
-      publish_key: self.publish_key.clone(),
-      subscribe_key: self.subscribe_key.clone(),
-      client_uuid: self.client_uuid.clone(),
-      channel: channel_c,
-      group: group_c,
-    };
+  /// Like `subscribe`, but with `SubscribeOptions`.
+  pub fn subscribe_with<T: DeserializeOwned + Send + Sync>(
+    &self,
+    channel: Option<&str>,
+    group: Option<&str>,
+    options: SubscribeOptions,
+  ) -> Subscription<T> {
+    let channel_c = channel.map(|c| CString::new(c).expect("UTF-8 doesn't include nul"));
+    let group_c = group.map(|g| CString::new(g).expect("UTF-8 doesn't include nul"));
 
-    Subscription::new(config)
+    Subscription::new(
+      self,
+      channel_c,
+      group_c,
+      options,
+      envelope::payload_callback::<T>,
+      envelope::subscribe_v2,
+    )
   }
 
-  pub fn publish<T: Serialize>(&self, channel: &str, group: &str, body: T) -> PublishFuture {
-    let channel_c = CString::new(channel).expect("UTF-8 doesn't include nul");
-    let group_c = CString::new(group).expect("UTF-8 doesn't include nul");
+  pub fn publish<T: Serialize>(&self, channel: &str, body: T) -> PublishFuture {
+    self.publish_with(channel, body, PublishOptions::default())
+  }
+
+  /// How the publish context pool is being used, if `ClientConfig::publish_pool` was set.
+  pub fn publish_pool_stats(&self) -> Option<PoolStats> {
+    self.publish_pool.as_ref().map(|pool| pool.stats())
+  }
 
-    let config = ChannelConfig {
+  // What identifies a client.  Everything else in a `Client` is a handle to something it shares with its clones.
+  fn id(&self) -> (&CString, &CString, &CString, &CString) {
+    (
+      &self.auth_key,
+      &self.publish_key,
+      &self.subscribe_key,
+      &self.client_uuid,
+    )
+  }
+
+  fn channel_config(&self, channel: Option<CString>, group: Option<CString>) -> ChannelConfig {
+    ChannelConfig {
       auth_key: self.auth_key.clone(),
       publish_key: self.publish_key.clone(),
       subscribe_key: self.subscribe_key.clone(),
       client_uuid: self.client_uuid.clone(),
-      channel: channel_c,
-      group: group_c,
-    };
+      channel,
+      group,
+    }
+  }
+}
 
-    // TODO: we may want a context pool as each context consumes significant resources.
-    PublishFuture::new(config, body)
+impl PartialEq for Client {
+  fn eq(&self, other: &Self) -> bool {
+    self.id() == other.id()
+  }
+}
+
+impl Eq for Client {}
+
+impl PartialOrd for Client {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Client {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    self.id().cmp(&other.id())
+  }
+}
+
+impl std::hash::Hash for Client {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.id().hash(state)
+  }
+}
+
+#[cfg(test)]
+impl Client {
+  // A client with empty keys and the default config, built without `try_new` so that tests don't touch c-core.
+  pub(crate) fn for_test() -> Self {
+    Self {
+      auth_key: CString::default(),
+      publish_key: CString::default(),
+      subscribe_key: CString::default(),
+      client_uuid: CString::default(),
+      heartbeat: Arc::default(),
+      publish_pool: None,
+      codec: Arc::new(Json),
+      cipher: None,
+      reconnect_timer: Arc::default(),
+    }
   }
 }
 
 pub struct Subscription<T> {
+  rx: QueueReceiver<Result<T, ClientError>>,
+  // The latest timetoken saved by the callback, which may be ahead of the messages we have yielded so far.
+  latest_timetoken: Arc<Mutex<Option<String>>>,
+  timetoken: Option<String>,
+  // Taken by `unsubscribe`, otherwise torn down on drop.
+  context: Option<SubscribeContext>,
+  connection_status: Option<ConnectionStatusStream>,
+  dead_letter: Arc<Mutex<Option<DeadLetterFn>>>,
+}
+
+// The C side of a `Subscription`.
+struct SubscribeContext {
   ctx: *mut pubnub_t,
-  rx: Receiver<Result<T, ClientError>>,
-  // We hold on to this so that we can free the memory later.
-  user_data: *mut SubscribeUserData<T>,
-  // We pass refs of these to C land.  We keep them around here so they will not be freed until the `Subscription` is dropped.
-  _auth_key: CString,
-  _publish_key: CString,
-  _subscribe_key: CString,
-  _client_uuid: CString,
-  _channel: CString,
-  _group: CString,
+  status: Arc<SubscribeStatus>,
+  // We hold on to this so that we can free the memory later.  It is type-erased so that the context can be torn down on
+  // another thread, whatever the message type.
+  user_data: *mut std::ffi::c_void,
+  drop_user_data: unsafe fn(*mut std::ffi::c_void),
+  subscribe: SubscribeFn,
+  // We pass refs of these to C land.  We keep them around here so they will not be freed until the context is, and to
+  // leave the channels removed from the subscription with.
+  auth_key: CString,
+  publish_key: CString,
+  subscribe_key: CString,
+  client_uuid: CString,
+  target: Arc<Mutex<SubscribeTarget>>,
+  // Our entry in the channels the heartbeat keeps us present on.
+  subscribed: Arc<Mutex<SubscribedChannels>>,
+  subscribed_id: u64,
+}
+
+unsafe impl Send for SubscribeContext {}
+
+impl SubscribeContext {
+  // Stops the long-poll, sends a leave for the subscribed channels and groups, and frees the context once PubNub has
+  // acknowledged the leave.  Blocks while waiting on the callback, so this should not be called from a task.
+  fn leave(self) -> Result<(), ClientError> {
+    self.subscribed.lock().unwrap().remove(self.subscribed_id);
+
+    let status = self.status.clone();
+    let stopping = {
+      let mut state = status.state.lock().unwrap();
+      match *state {
+        SubscribeState::Polling => *state = SubscribeState::Stopping,
+        // Tells the scheduled reconnect not to.
+        SubscribeState::Waiting => {
+          *state = SubscribeState::Idle;
+          status.changed.notify_all();
+        }
+        _ => {}
+      }
+      *state == SubscribeState::Stopping
+    };
+    // We must not hold the lock here, as c-core may be waiting to call the callback with its own lock held.
+    if stopping {
+      unsafe { pubnub_cancel(self.ctx) };
+    }
+    if status.wait_while(|s| s == SubscribeState::Stopping) != SubscribeState::Idle {
+      return self.abandon();
+    }
+
+    status.set(SubscribeState::Leaving);
+    let result = unsafe {
+      let target = self.target.lock().unwrap();
+      pubnub_leave(self.ctx, as_ptr_or_null(&target.channel), as_ptr_or_null(&target.group))
+    };
+    let result = if result == pubnub_res_PNR_STARTED {
+      match status.wait_while(|s| s == SubscribeState::Leaving) {
+        SubscribeState::Left(result) => result,
+        _ => return self.abandon(),
+      }
+    } else {
+      result
+    };
+
+    unsafe {
+      if pubnub_free(self.ctx) != 0 {
+        return self.abandon();
+      }
+      (self.drop_user_data)(self.user_data); // This is safe because the callback will not be invoked again.
+    }
+    if result == pubnub_res_PNR_OK {
+      Ok(())
+    } else {
+      Err(ClientError::PubNub { kind: result.into() })
+    }
+  }
+
+  // Changes the subscribed channels and groups, then restarts the long-poll so that the change takes effect.  c-core
+  // keeps the timetoken of the last long-poll, so nothing published in between is missed.
+  fn edit(&self, edit: impl FnOnce(&mut SubscribeTarget)) -> Result<(), ClientError> {
+    let (channel, group) = {
+      let mut target = self.target.lock().unwrap();
+      edit(&mut target);
+      (target.channel.clone(), target.group.clone())
+    };
+    self
+      .subscribed
+      .lock()
+      .unwrap()
+      .update(self.subscribed_id, channel, group);
+
+    let mut state = self.status.state.lock().unwrap();
+    match *state {
+      SubscribeState::Polling => {
+        drop(state);
+        // The callback restarts the long-poll with the new target once the cancellation completes.  As in `leave`, we
+        // must not hold the lock here.
+        unsafe { pubnub_cancel(self.ctx) };
+        Ok(())
+      }
+      SubscribeState::Idle => {
+        let target = self.target.lock().unwrap();
+        if target.is_empty() {
+          return Ok(());
+        }
+        let result = unsafe { (self.subscribe)(self.ctx, &target) };
+        if result == pubnub_res_PNR_STARTED {
+          *state = SubscribeState::Polling;
+          self.status.changed.notify_all();
+          Ok(())
+        } else {
+          Err(ClientError::PubNub { kind: result.into() })
+        }
+      }
+      // Either a reconnect is pending, which will pick up the change, or the subscription is being torn down.
+      _ => Ok(()),
+    }
+  }
+
+  // Tells PubNub that we have left `channel` and `group`, once they have been removed from the subscription.  The
+  // context is busy long-polling the rest, so the leave goes out on a context of its own.
+  fn leave_removed(&self, channel: Option<CString>, group: Option<CString>) {
+    if channel.is_none() && group.is_none() {
+      return;
+    }
+    let leave = TransactionFuture::new(
+      ChannelConfig {
+        auth_key: self.auth_key.clone(),
+        publish_key: self.publish_key.clone(),
+        subscribe_key: self.subscribe_key.clone(),
+        client_uuid: self.client_uuid.clone(),
+        channel,
+        group,
+      },
+      pubnub_trans_PBTT_LEAVE,
+      Box::new(|ctx, config| unsafe {
+        pubnub_leave(ctx, as_ptr_or_null(&config.channel), as_ptr_or_null(&config.group))
+      }),
+      Box::new(|_| Ok(())),
+    );
+    // As when dropping a subscription, we shouldn't wait on PubNub wherever we are called from.
+    std::thread::spawn(move || {
+      leave
+        .wait()
+        .map_err(|e| eprintln!("Unable to leave channels removed from a subscription: {}", e))
+        .ok()
+    });
+  }
+
+  // If PubNub never gets back to us the callback may yet run, so we can free neither the context nor the user data.
+  fn abandon(self) -> Result<(), ClientError> {
+    eprintln!("Timed out tearing down a subscription, leaking its context");
+    std::mem::forget(self);
+    Err(ClientError::PubNub {
+      kind: PubNubErrorKind::Timeout,
+    })
+  }
 }
 
 // The pointers in `Subscription` are thread-safe, so we can implement this.
@@ -134,8 +743,15 @@ pub struct Subscription<T> {
 unsafe impl<T> Send for Subscription<T> {}
 unsafe impl<T> Sync for Subscription<T> {}
 
-impl<'a, T: Send + Sync + Deserialize<'a>> Subscription<T> {
-  fn new(config: ChannelConfig) -> Self {
+impl<T> Subscription<T> {
+  fn new(
+    client: &Client,
+    channel: Option<CString>,
+    group: Option<CString>,
+    options: SubscribeOptions,
+    callback: SubscribeCallback,
+    subscribe: SubscribeFn,
+  ) -> Self {
     let ChannelConfig {
       auth_key,
       publish_key,
@@ -143,13 +759,48 @@ impl<'a, T: Send + Sync + Deserialize<'a>> Subscription<T> {
       client_uuid,
       channel,
       group,
-    } = config;
+    } = client.channel_config(channel, group);
+    let heartbeat = &client.heartbeat;
+
+    let SubscribeOptions {
+      timetoken,
+      reconnect,
+      capacity,
+      overflow,
+    } = options;
 
-    let (tx, rx) = futures::sync::mpsc::channel::<Result<T, ClientError>>(10);
+    let (tx, rx) = queue::queue(capacity, overflow);
+    let (status_tx, status_rx) = futures::sync::mpsc::channel(10);
+    let latest_timetoken = Arc::new(Mutex::new(timetoken.clone()));
+    let dead_letter = Arc::new(Mutex::new(None));
+
+    let status = Arc::new(SubscribeStatus {
+      state: Mutex::new(SubscribeState::Polling),
+      changed: Condvar::new(),
+    });
+
+    let subscribed = heartbeat.subscribed();
+    let subscribed_id = subscribed.lock().unwrap().add(&channel, &group);
+    let target = Arc::new(Mutex::new(SubscribeTarget {
+      channel,
+      group,
+      heartbeat: heartbeat.presence_timeout(),
+    }));
 
     let user_data = Box::into_raw(Box::new(SubscribeUserData {
       tx,
-      channel: channel.clone(), // TODO: can this just be a reference?
+      target: target.clone(),
+      subscribe,
+      status: status.clone(),
+      timetoken: latest_timetoken.clone(),
+      status_tx,
+      reconnect,
+      attempts: 0,
+      connected: false,
+      dead_letter: dead_letter.clone(),
+      codec: client.codec.clone(),
+      cipher: client.cipher.clone(),
+      timer: client.reconnect_timer.clone(),
     }));
 
     let ctx = unsafe {
@@ -157,21 +808,141 @@ impl<'a, T: Send + Sync + Deserialize<'a>> Subscription<T> {
       pubnub_init(ctx, publish_key.as_ptr(), subscribe_key.as_ptr());
       pubnub_set_uuid(ctx, client_uuid.as_ptr());
       pubnub_set_auth(ctx, auth_key.as_ptr());
-      pubnub_register_callback(ctx, Some(subscribe_callback::<T>), user_data as *mut std::ffi::c_void);
-      pubnub_subscribe(ctx, channel.as_ptr(), std::ptr::null()); // TODO: technically we shouldn't call this line until the stream gets polled the first time.
+      pubnub_register_callback(ctx, Some(callback), user_data as *mut std::ffi::c_void);
+      if let Some(ref tt) = timetoken {
+        let tt_c = CString::new(tt.as_str()).expect("UTF-8 doesn't include nul");
+        pubnub_set_timetoken(ctx, tt_c.as_ptr());
+      }
+      // TODO: technically we shouldn't call this line until the stream gets polled the first time.
+      let result = subscribe(ctx, &target.lock().unwrap());
+      if result != pubnub_res_PNR_STARTED {
+        // The callback is never invoked when a transaction fails to start (e.g. neither a channel nor a group was
+        // given), so report the error here or the stream would never yield anything.
+        (*user_data).send(Err(ClientError::PubNub { kind: result.into() }));
+        status.set(SubscribeState::Idle);
+      }
       ctx
     };
 
     Self {
-      ctx,
       rx,
-      _channel: channel,
-      _auth_key: auth_key,
-      _publish_key: publish_key,
-      _subscribe_key: subscribe_key,
-      _group: group,
-      _client_uuid: client_uuid,
-      user_data,
+      latest_timetoken,
+      timetoken,
+      context: Some(SubscribeContext {
+        ctx,
+        status,
+        user_data: user_data as *mut std::ffi::c_void,
+        drop_user_data: drop_user_data::<T>,
+        subscribe,
+        target,
+        subscribed,
+        subscribed_id,
+        auth_key,
+        publish_key,
+        subscribe_key,
+        client_uuid,
+      }),
+      connection_status: Some(ConnectionStatusStream { rx: status_rx }),
+      dead_letter,
+    }
+  }
+}
+
+impl<T> Subscription<T> {
+  /// The timetoken to resume from (via `SubscribeOptions::timetoken`) so that no message after the ones already
+  /// yielded by this stream is missed.  `None` until the first long-poll completes, unless a starting timetoken was
+  /// given.
+  pub fn timetoken(&self) -> Option<String> {
+    self.timetoken.clone()
+  }
+
+  /// Yields each message and error as a `Result`, so that a single bad message doesn't end the stream.
+  pub fn results(self) -> SubscriptionResults<T> {
+    SubscriptionResults { subscription: self }
+  }
+
+  /// Hands messages that can't be decoded to `dead_letter` instead of yielding them as errors.  The `JsonError` keeps
+  /// the raw JSON along with the channel and timetoken the message arrived with.
+  pub fn set_dead_letter(&self, dead_letter: impl FnMut(JsonError) + Send + 'static) {
+    *self.dead_letter.lock().unwrap() = Some(Box::new(dead_letter));
+  }
+
+  /// How many messages have been dropped because the buffer was full.  See `SubscribeOptions::overflow`.
+  pub fn dropped(&self) -> u64 {
+    self.rx.dropped()
+  }
+
+  /// Takes the stream of connection status changes.  Returns `None` if it has already been taken.
+  pub fn connection_status(&mut self) -> Option<ConnectionStatusStream> {
+    self.connection_status.take()
+  }
+
+  /// Starts listening to `channels` as well, without missing any message on the channels already subscribed to.
+  pub fn add_channels(&self, channels: &[&str]) -> Result<(), ClientError> {
+    self.edit(|target| target.channel = edit_names(&target.channel, channels, &[]))
+  }
+
+  /// Stops listening to `channels`, and tells PubNub that this client has left them without waiting for it.  Removing
+  /// every channel and group pauses the subscription until one is added.
+  pub fn remove_channels(&self, channels: &[&str]) -> Result<(), ClientError> {
+    let mut removed = None;
+    let result = self.edit(|target| {
+      removed = kept_names(&target.channel, channels);
+      target.channel = edit_names(&target.channel, &[], channels);
+    });
+    self.context().leave_removed(removed, None);
+    result
+  }
+
+  /// Starts listening to the channels in `groups` as well.
+  pub fn add_groups(&self, groups: &[&str]) -> Result<(), ClientError> {
+    self.edit(|target| target.group = edit_names(&target.group, groups, &[]))
+  }
+
+  /// Stops listening to the channels in `groups`, and tells PubNub that this client has left them without waiting for
+  /// it.
+  pub fn remove_groups(&self, groups: &[&str]) -> Result<(), ClientError> {
+    let mut removed = None;
+    let result = self.edit(|target| {
+      removed = kept_names(&target.group, groups);
+      target.group = edit_names(&target.group, &[], groups);
+    });
+    self.context().leave_removed(None, removed);
+    result
+  }
+
+  fn edit(&self, edit: impl FnOnce(&mut SubscribeTarget)) -> Result<(), ClientError> {
+    self.context().edit(edit)
+  }
+
+  fn context(&self) -> &SubscribeContext {
+    self.context.as_ref().expect("the context is only taken by unsubscribe")
+  }
+
+  /// Stops the subscription and tells PubNub that this client has left its channels.  Resolves once the leave has been
+  /// acknowledged.  Dropping a `Subscription` also sends a leave, but without waiting for it.
+  pub fn unsubscribe(mut self) -> Unsubscribe {
+    let (tx, rx) = futures::sync::oneshot::channel();
+    let context = self.context.take().expect("the context is only taken once");
+    std::thread::spawn(move || tx.send(context.leave()));
+    Unsubscribe { rx }
+  }
+}
+
+/// The future returned by `Subscription::unsubscribe`.
+pub struct Unsubscribe {
+  rx: futures::sync::oneshot::Receiver<Result<(), ClientError>>,
+}
+
+impl Future for Unsubscribe {
+  type Item = ();
+  type Error = ClientError;
+
+  fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+    match self.rx.poll() {
+      Ok(Async::Ready(res)) => res.map(Async::Ready),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(_) => Err(ClientError::PollError),
     }
   }
 }
@@ -181,119 +952,274 @@ impl<T: std::fmt::Debug> Stream for Subscription<T> {
   type Error = ClientError;
 
   fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+    // The callback saves a timetoken only after queuing all of its messages, so if the queue is empty *after* we read
+    // the timetoken, every message up to that timetoken has been yielded.
+    let latest_timetoken = self.latest_timetoken.lock().unwrap().clone();
     match self.rx.poll() {
-      Ok(Async::Ready(Some(Ok(t)))) => Ok(Async::Ready(Some(t))),
-      Ok(Async::Ready(Some(Err(e)))) => Err(e),
-      Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
-      Ok(Async::NotReady) => Ok(Async::NotReady),
-      Err(()) => panic!("Received error from an mpsc channel, this shouldn't be possible."),
+      Async::Ready(Some(Ok(t))) => Ok(Async::Ready(Some(t))),
+      Async::Ready(Some(Err(e))) => Err(e),
+      Async::Ready(None) => Ok(Async::Ready(None)),
+      Async::NotReady => {
+        self.timetoken = latest_timetoken;
+        Ok(Async::NotReady)
+      }
     }
   }
 }
 
-unsafe extern "C" fn subscribe_callback<'a, T: Deserialize<'a>>(
-  pb: *mut pubnub_t,
-  trans: pubnub_trans,
-  result: pubnub_res,
-  user_data: *mut ::std::os::raw::c_void,
-) {
-  let ud: &mut SubscribeUserData<T> = &mut *(user_data as *mut SubscribeUserData<T>); // TODO: verify that this callback can only happen once at a time, or wrap in a mutex.
-  if trans == pubnub_trans_PBTT_SUBSCRIBE {
-    let maybe_res: Option<Result<T, ClientError>> = if result == pubnub_res_PNR_OK {
-      let ptr = pubnub_get(pb);
-      if !ptr.is_null() {
-        let c = std::ffi::CStr::from_ptr(ptr);
-        let s = c.to_str().unwrap(); // TODO: return error if that is needed.
-
-        Some(serde_json::from_str::<T>(s).map_err(|e| ClientError::ParseError(JsonError { err: e })))
-      } else {
-        // If the pointer is not here don't return an error, just run again; we get a null on the first call.
-        None
-      }
-    } else {
-      Some(Err(ClientError::PubNub { code: result }))
-    };
-    maybe_res.map(|res| {
-      ud.tx
-        .try_send(res)
-        .map_err(|e| println!("subscribe callback unable to send {:?}", e))
-        .ok()
-    }); // We shouldn't need to notify, because that is taken care of by the channel.
+/// A `Subscription` that yields errors as items, from `Subscription::results`.  It never fails, so combinators like
+/// `for_each` keep going past a message that could not be decoded.
+pub struct SubscriptionResults<T> {
+  subscription: Subscription<T>,
+}
+
+impl<T> SubscriptionResults<T> {
+  pub fn get_ref(&self) -> &Subscription<T> {
+    &self.subscription
   }
 
-  // TODO: verify that we are happy with this here.  PubNub docs suggest that it is ok to do operations like this inside of a callback, but not recommended (as it can make debugging harder).  Our use case is simple (a loop), so maybe we're ok?
-  pubnub_subscribe(pb, ud.channel.as_ptr(), std::ptr::null());
+  pub fn get_mut(&mut self) -> &mut Subscription<T> {
+    &mut self.subscription
+  }
+
+  pub fn into_inner(self) -> Subscription<T> {
+    self.subscription
+  }
+}
+
+impl<T: std::fmt::Debug> Stream for SubscriptionResults<T> {
+  type Item = Result<T, ClientError>;
+  type Error = ClientError;
+
+  fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+    match self.subscription.poll() {
+      Ok(Async::Ready(Some(t))) => Ok(Async::Ready(Some(Ok(t)))),
+      Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(e) => Ok(Async::Ready(Some(Err(e)))),
+    }
+  }
 }
 
 impl<T> Drop for Subscription<T> {
   fn drop(&mut self) {
-    unsafe {
-      pubnub_free(self.ctx);
-      Box::from_raw(self.user_data); // This should be safe because we have cleared the mutable ref held by self.ctx
-    };
+    if let Some(context) = self.context.take() {
+      // Leaving means waiting on PubNub, which we shouldn't do wherever we are being dropped.
+      std::thread::spawn(move || {
+        context
+          .leave()
+          .map_err(|e| eprintln!("Unable to leave after dropping a subscription: {}", e))
+          .ok()
+      });
+    }
   }
 }
 
-struct PublishFutureUserData {
+struct TransactionUserData<R> {
   task: Task,
-  tx: Sender<Result<(), ClientError>>,
+  tx: Sender<Result<R, ClientError>>,
+  trans: pubnub_trans,
+  parse: ParseFn<R>,
+  completion: Arc<Completion>,
+}
+
+unsafe fn drop_transaction_user_data<R>(user_data: *mut std::ffi::c_void) {
+  drop(Box::from_raw(user_data as *mut TransactionUserData<R>));
+}
+
+// Set once c-core is done with a transaction's context and user data: by the callback as the last thing it does, or by
+// the future if the transaction never started.
+#[derive(Default)]
+struct Completion {
+  done: Mutex<bool>,
+  changed: Condvar,
+}
+
+impl Completion {
+  fn set(&self) {
+    *self.done.lock().unwrap() = true;
+    self.changed.notify_all();
+  }
+
+  fn is_set(&self) -> bool {
+    *self.done.lock().unwrap()
+  }
+
+  // Returns whether it was set within `LEAVE_TIMEOUT`.
+  fn wait(&self) -> bool {
+    let done = self.done.lock().unwrap();
+    let (done, _) = self
+      .changed
+      .wait_timeout_while(done, LEAVE_TIMEOUT, |done| !*done)
+      .unwrap();
+    *done
+  }
+}
+
+// What a dropped `TransactionFuture` has to dispose of once c-core is done with it.
+struct Leftovers {
+  // Null if the future held no context.
+  ctx: *mut pubnub_t,
+  pool: Option<Arc<ContextPool>>,
+  // Null if the transaction never started.
+  user_data: *mut std::ffi::c_void,
+  drop_user_data: unsafe fn(*mut std::ffi::c_void),
+  // c-core may be reading the message and keys out of these until the transaction is done.
+  start: StartFn,
+  config: ChannelConfig,
+}
+
+unsafe impl Send for Leftovers {}
+
+impl Leftovers {
+  // Only once the callback has run, or if it never will.
+  unsafe fn free(self) {
+    if !self.ctx.is_null() {
+      match self.pool {
+        Some(ref pool) => pool.release(self.ctx),
+        None if pubnub_free(self.ctx) != 0 => return self.leak(),
+        None => {}
+      }
+    }
+    if !self.user_data.is_null() {
+      (self.drop_user_data)(self.user_data);
+    }
+  }
+
+  // If PubNub never gets back to us the callback may yet run, so we can free neither the context nor the user data.
+  fn leak(self) {
+    eprintln!("Unable to tear down a transaction, leaking its context");
+    let Leftovers {
+      pool, start, config, ..
+    } = self;
+    if let Some(pool) = pool {
+      pool.discard();
+    }
+    std::mem::forget((start, config));
+  }
 }
 
-unsafe extern "C" fn publish_callback(
-  _pb: *mut pubnub_t,
+// Reads the outcome of a successful transaction out of the context.
+type ParseFn<R> = Box<dyn FnMut(*mut pubnub_t) -> Result<R, ClientError> + Send>;
+// Starts a transaction on the context, returning the result of the c-core call.
+type StartFn = Box<dyn FnMut(*mut pubnub_t, &ChannelConfig) -> pubnub_res + Send>;
+
+unsafe extern "C" fn transaction_callback<R>(
+  pb: *mut pubnub_t,
   trans: pubnub_trans,
   result: pubnub_res,
   user_data: *mut ::std::os::raw::c_void,
 ) {
-  if trans == pubnub_trans_PBTT_PUBLISH {
+  let ud: &mut TransactionUserData<R> = &mut *(user_data as *mut TransactionUserData<R>);
+  let completion = ud.completion.clone();
+  if trans == ud.trans {
     let res = if result == pubnub_res_PNR_OK {
-      Ok(())
+      (ud.parse)(pb)
+    } else if trans == pubnub_trans_PBTT_PUBLISH || trans == pubnub_trans_PBTT_SIGNAL {
+      Err(publish_error(pb, result))
     } else {
-      Err(ClientError::PubNub { code: result })
+      Err(ClientError::PubNub { kind: result.into() })
     };
 
-    let ud: &mut PublishFutureUserData = &mut *(user_data as *mut PublishFutureUserData);
     ud.tx
       .try_send(res)
-      .map_err(|e| println!("publish callback unable to send {:?}", e))
+      .map_err(|e| println!("transaction callback unable to send {:?}", e))
       .ok();
     ud.task.notify();
   }
+  // Last, as the future may free the user data as soon as this is set.
+  completion.set();
+}
+
+// c-core leaves the reply to a failed publish in the context, so we can report what the server said about it.
+unsafe fn publish_error(pb: *mut pubnub_t, result: pubnub_res) -> ClientError {
+  let string = |ptr| owned_string(ptr).filter(|s| !s.is_empty());
+  let http_status = pubnub_last_http_code(pb);
+
+  ClientError::Publish {
+    kind: result.into(),
+    http_status: if http_status > 0 {
+      Some(http_status as u16)
+    } else {
+      None
+    },
+    message: string(pubnub_last_publish_result(pb)),
+    body: string(pubnub_get(pb)),
+  }
+}
+
+// Serializes and encodes a message to publish.  c-core takes the message as a C string, so one that a codec encoded
+// with a nul in it is an error rather than a panic.
+fn encode_message<M: Serialize>(codec: &dyn Codec, message: M) -> Result<String, serde_json::Error> {
+  let encoded = serde_json::to_value(&message).and_then(|value| codec.encode(value))?;
+  if encoded.contains('\0') {
+    return Err(serde::ser::Error::custom("an encoded message must not include nul"));
+  }
+  Ok(encoded)
+}
+
+// Decrypts a received message, if there is a cipher key, and decodes it.
+fn decode_message<M: DeserializeOwned>(
+  codec: &dyn Codec,
+  cipher: Option<&Cipher>,
+  message: &str,
+) -> Result<M, ClientError> {
+  let decrypted;
+  let message = match cipher {
+    Some(cipher) => {
+      decrypted = cipher.decrypt(message)?;
+      &decrypted
+    }
+    None => message,
+  };
+  codec
+    .decode(message)
+    .and_then(serde_json::from_value)
+    .map_err(|e| ClientError::ParseError(JsonError::new(e, message)))
+}
+
+/// Reads the JSON response of the last transaction and deserializes it.
+unsafe fn parse_json<R: DeserializeOwned>(pb: *mut pubnub_t) -> Result<R, ClientError> {
+  let ptr = pubnub_get(pb);
+  if ptr.is_null() {
+    return Err(ClientError::NoResponse);
+  }
+  let s = std::ffi::CStr::from_ptr(ptr).to_string_lossy();
+  serde_json::from_str::<R>(&s).map_err(|e| ClientError::ParseError(JsonError::new(e, &s)))
 }
 
-pub struct PublishFuture {
+/// A single PubNub transaction (publish, channel group management, etc.) running on its own context.
+pub struct TransactionFuture<R> {
   // This would be a oneshot, but we can't get ownership of the tx end in the callback (to send the message), so we use mpsc as if it were a oneshot.
-  user_data: Option<*mut PublishFutureUserData>,
-  rx: Option<Receiver<Result<(), ClientError>>>,
+  user_data: Option<*mut TransactionUserData<R>>,
+  rx: Option<Receiver<Result<R, ClientError>>>,
+  // Null while waiting on `pool`, and once a pooled context has been returned.
   ctx: *mut pubnub_t,
-  channel: CString,
-  _auth_key: CString,
-  _publish_key: CString,
-  _subscribe_key: CString,
-  _group: CString,
-  _client_uuid: CString,
-  msg: CString,
+  pool: Option<Arc<ContextPool>>,
+  // Identifies this future in the pool's queue, once it has had to wait for a context.
+  waiter: Option<u64>,
+  completion: Arc<Completion>,
+  trans: pubnub_trans,
+  // Anything the c-core call needs to keep alive (e.g. the message) is owned by this closure.
+  start: StartFn,
+  // Handed over to the callback once the transaction starts.
+  parse: Option<ParseFn<R>>,
+  // We pass refs of these to C land.  We keep them around here so they will not be freed until the future is dropped.
+  config: ChannelConfig,
   started: bool,
+  // Set if the transaction could not be put together, to be returned instead of starting it.
+  error: Option<ClientError>,
 }
 
-impl PublishFuture {
-  fn new<T: Serialize>(config: ChannelConfig, msg: T) -> Self {
-    let msg_string = serde_json::to_string(&msg).unwrap();
-    let msg_c = CString::new(msg_string).unwrap();
-    let ChannelConfig {
-      publish_key,
-      subscribe_key,
-      client_uuid,
-      auth_key,
-      channel,
-      group,
-    } = config;
+pub type PublishFuture = TransactionFuture<()>;
 
+impl<R> TransactionFuture<R> {
+  fn new(config: ChannelConfig, trans: pubnub_trans, start: StartFn, parse: ParseFn<R>) -> Self {
     let ctx = unsafe {
       let ctx = pubnub_alloc();
-      pubnub_init(ctx, publish_key.as_ptr(), subscribe_key.as_ptr());
-      pubnub_set_uuid(ctx, client_uuid.as_ptr());
-      pubnub_set_auth(ctx, auth_key.as_ptr());
+      pubnub_init(ctx, config.publish_key.as_ptr(), config.subscribe_key.as_ptr());
+      pubnub_set_uuid(ctx, config.client_uuid.as_ptr());
+      pubnub_set_auth(ctx, config.auth_key.as_ptr());
       ctx
     };
 
@@ -302,53 +1228,198 @@ impl PublishFuture {
       user_data: None,
       rx: None,
       ctx,
-      channel,
-      _auth_key: auth_key,
-      _publish_key: publish_key,
-      _subscribe_key: subscribe_key,
-      _group: group,
-      _client_uuid: client_uuid,
-      msg: msg_c,
+      pool: None,
+      waiter: None,
+      completion: Arc::default(),
+      trans,
+      start,
+      parse: Some(parse),
+      config,
+      error: None,
+    }
+  }
+
+  // Like `new`, but checks a context out of `pool` (if given) when first polled instead of allocating one.
+  fn pooled(
+    config: ChannelConfig,
+    pool: Option<Arc<ContextPool>>,
+    trans: pubnub_trans,
+    start: StartFn,
+    parse: ParseFn<R>,
+  ) -> Self {
+    match pool {
+      Some(pool) => Self {
+        started: false,
+        user_data: None,
+        rx: None,
+        ctx: std::ptr::null_mut(),
+        pool: Some(pool),
+        waiter: None,
+        completion: Arc::default(),
+        trans,
+        start,
+        parse: Some(parse),
+        config,
+        error: None,
+      },
+      None => Self::new(config, trans, start, parse),
+    }
+  }
+
+  // A future that fails with `error` when polled, without ever allocating a context.
+  fn failed(config: ChannelConfig, trans: pubnub_trans, error: ClientError) -> Self {
+    Self {
+      started: false,
+      user_data: None,
+      rx: None,
+      ctx: std::ptr::null_mut(),
+      pool: None,
+      waiter: None,
+      completion: Arc::default(),
+      trans,
+      start: Box::new(|_, _| pubnub_res_PNR_INVALID_PARAMETERS),
+      parse: None,
+      config,
+      error: Some(error),
+    }
+  }
+
+  // Hands a pooled context back once c-core is done with it.
+  fn release(&mut self) {
+    if let Some(ref pool) = self.pool {
+      if !self.ctx.is_null() && self.completion.is_set() {
+        pool.release(self.ctx);
+        self.ctx = std::ptr::null_mut();
+      }
     }
   }
 }
 
-unsafe impl Send for PublishFuture {}
-unsafe impl Sync for PublishFuture {}
+impl PublishFuture {
+  fn publish<T: Serialize>(
+    config: ChannelConfig,
+    pool: Option<Arc<ContextPool>>,
+    codec: &dyn Codec,
+    cipher: Option<&Cipher>,
+    msg: T,
+    options: PublishOptions,
+  ) -> Self {
+    let msg_c = match encode_message(codec, msg) {
+      Ok(msg_string) => match cipher {
+        Some(cipher) => CString::new(cipher.encrypt(&msg_string)).expect("base64 doesn't include nul"),
+        None => CString::new(msg_string).expect("encode_message rejects nul"),
+      },
+      Err(e) => return Self::failed(config, pubnub_trans_PBTT_PUBLISH, e.into()),
+    };
+    let meta_c = options.meta_c();
+
+    Self::pooled(
+      config,
+      pool,
+      pubnub_trans_PBTT_PUBLISH,
+      Box::new(move |ctx, config| unsafe {
+        pubnub_publish_ex(
+          ctx,
+          as_ptr_or_null(&config.channel),
+          msg_c.as_ptr(),
+          options.to_c(&meta_c),
+        )
+      }),
+      Box::new(|_| Ok(())),
+    )
+  }
+}
 
-impl Drop for PublishFuture {
+unsafe impl<R> Send for TransactionFuture<R> {}
+unsafe impl<R> Sync for TransactionFuture<R> {}
+
+impl<R> Drop for TransactionFuture<R> {
   fn drop(&mut self) {
-    unsafe {
-      pubnub_free(self.ctx);
-      if let Some(ud) = self.user_data {
-        Box::from_raw(ud); // This should be safe because we have cleared the mutable ref held by self.ctx
-      }
+    if let Some(ref pool) = self.pool {
+      pool.unqueue(self.waiter);
     }
+    let config = self.config.clone();
+    let leftovers = Leftovers {
+      ctx: self.ctx,
+      pool: self.pool.take(),
+      user_data: self
+        .user_data
+        .map_or(std::ptr::null_mut(), |ud| ud as *mut std::ffi::c_void),
+      drop_user_data: drop_transaction_user_data::<R>,
+      start: std::mem::replace(&mut self.start, Box::new(|_, _| pubnub_res_PNR_INVALID_PARAMETERS)),
+      config: std::mem::replace(&mut self.config, config),
+    };
+    if self.user_data.is_none() || self.completion.is_set() {
+      unsafe { leftovers.free() };
+      return;
+    }
+
+    // The transaction is in flight, so the callback is yet to run.  Waiting for it means waiting on PubNub, which we
+    // shouldn't do wherever we are being dropped.
+    unsafe { pubnub_cancel(self.ctx) };
+    let completion = self.completion.clone();
+    std::thread::spawn(move || {
+      if completion.wait() {
+        unsafe { leftovers.free() }
+      } else {
+        leftovers.leak()
+      }
+    });
   }
 }
 
-impl Future for PublishFuture {
-  type Item = ();
+impl<R> Future for TransactionFuture<R> {
+  type Item = R;
   type Error = ClientError;
 
   fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+    if let Some(error) = self.error.take() {
+      self.started = true;
+      return Err(error);
+    }
     if !self.started {
+      if let Some(ref pool) = self.pool {
+        match pool.checkout(&mut self.waiter)? {
+          Async::Ready(ctx) => self.ctx = ctx,
+          Async::NotReady => return Ok(Async::NotReady),
+        }
+      }
       self.started = true;
-      let (tx, rx) = futures::sync::mpsc::channel::<Result<(), ClientError>>(0);
+      let (tx, rx) = futures::sync::mpsc::channel::<Result<R, ClientError>>(0);
       self.rx = Some(rx);
-      let user_data = Box::into_raw(Box::new(PublishFutureUserData {
+      let user_data = Box::into_raw(Box::new(TransactionUserData {
         tx,
         task: futures::task::current(),
+        trans: self.trans,
+        parse: self.parse.take().expect("a transaction is only started once"),
+        completion: self.completion.clone(),
       }));
-      unsafe {
-        pubnub_register_callback(self.ctx, Some(publish_callback), user_data as *mut std::ffi::c_void);
-        pubnub_publish(self.ctx, self.channel.as_ptr(), self.msg.as_ptr());
-      }
       self.user_data = Some(user_data);
+      let result = unsafe {
+        pubnub_register_callback(
+          self.ctx,
+          Some(transaction_callback::<R>),
+          user_data as *mut std::ffi::c_void,
+        );
+        (self.start)(self.ctx, &self.config)
+      };
+      if result != pubnub_res_PNR_STARTED {
+        // The callback will never be invoked for a transaction that failed to start.
+        self.completion.set();
+        self.release();
+        return Err(ClientError::PubNub { kind: result.into() });
+      }
       Ok(Async::NotReady)
     } else if let Some(ref mut rx) = self.rx {
-      match rx.poll() {
-        Ok(Async::Ready(Some(Ok(())))) => Ok(Async::Ready(())),
+      let res = rx.poll();
+      // The callback sends the result just before it returns, so this won't wait long.
+      if let Ok(Async::Ready(Some(_))) = res {
+        if self.completion.wait() {
+          self.release();
+        }
+      }
+      match res {
+        Ok(Async::Ready(Some(Ok(r)))) => Ok(Async::Ready(r)),
         Ok(Async::Ready(Some(Err(e)))) => Err(e),
         Ok(Async::Ready(None)) => Ok(Async::NotReady),
         Ok(Async::NotReady) => Ok(Async::NotReady),
@@ -364,7 +1435,47 @@ impl Future for PublishFuture {
 pub enum ClientError {
   ParseError(JsonError),
   PollError,
-  PubNub { code: pubnub_res },
+  NoResponse,
+  PubNub {
+    kind: PubNubErrorKind,
+  },
+  /// A publish was rejected or could not be sent.
+  Publish {
+    kind: PubNubErrorKind,
+    /// The HTTP status of PubNub's reply, if there was one.
+    http_status: Option<u16>,
+    /// PubNub's description of the outcome, e.g. "Invalid JSON".
+    message: Option<String>,
+    /// The body of PubNub's reply, usually a JSON object with the details of the error.
+    body: Option<String>,
+  },
+  /// Every context in the publish pool was in use, and `PoolConfig::when_exhausted` is `WhenExhausted::Error`.
+  PoolExhausted,
+  /// A message could not be decrypted with `ClientConfig::cipher_key`, e.g. because it was published with another
+  /// key or not encrypted at all.
+  Decrypt {
+    /// The message as it was received.
+    raw: String,
+  },
+  /// A client's config, or the options of a transaction, were rejected before anything was sent.
+  InvalidOptions {
+    reason: &'static str,
+  },
+  /// A message was too large to send, e.g. a signal over 64 bytes.
+  TooLarge {
+    size: usize,
+    max: usize,
+  },
+}
+
+impl ClientError {
+  // Records where a message that could not be decoded arrived.
+  fn arrived(self, channel: Option<String>, timetoken: Option<String>) -> Self {
+    match self {
+      ClientError::ParseError(e) => ClientError::ParseError(e.arrived(channel, timetoken)),
+      e => e,
+    }
+  }
 }
 
 impl std::fmt::Display for ClientError {
@@ -372,11 +1483,46 @@ impl std::fmt::Display for ClientError {
     match self {
       ClientError::ParseError(e) => write!(f, "PubNub client parse error: {}", e),
       ClientError::PollError => write!(f, "PubNub client poll error"),
-      ClientError::PubNub { code } => write!(f, "PubNub client error with code {}", code), // TODO: it would be nice to do these codes as an enum, but bindgen does not recommend directly building enums, as we do not own the c code.
+      ClientError::NoResponse => write!(f, "PubNub client received no response"),
+      ClientError::PoolExhausted => write!(f, "PubNub client publish pool exhausted"),
+      ClientError::Decrypt { .. } => write!(f, "PubNub client unable to decrypt message"),
+      ClientError::InvalidOptions { reason } => write!(f, "PubNub client invalid options: {}", reason),
+      ClientError::TooLarge { size, max } => write!(
+        f,
+        "PubNub client message too large: {} bytes, at most {} allowed",
+        size, max
+      ),
+      ClientError::PubNub { kind } => write!(f, "PubNub client error: {}", kind),
+      ClientError::Publish {
+        kind,
+        http_status,
+        message,
+        body,
+      } => {
+        write!(f, "PubNub client publish failed: {}", kind)?;
+        if let Some(status) = http_status {
+          write!(f, " (HTTP {})", status)?;
+        }
+        if let Some(message) = message.as_ref().or(body.as_ref()) {
+          write!(f, ": {}", message)?;
+        }
+        Ok(())
+      }
     }
   }
 }
 
+impl From<serde_json::Error> for ClientError {
+  fn from(err: serde_json::Error) -> Self {
+    ClientError::ParseError(JsonError {
+      err,
+      raw: None,
+      channel: None,
+      timetoken: None,
+    })
+  }
+}
+
 impl std::error::Error for ClientError {
   fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
     match self {
@@ -389,6 +1535,44 @@ impl std::error::Error for ClientError {
 #[derive(Debug)]
 pub struct JsonError {
   err: serde_json::Error,
+  raw: Option<String>,
+  channel: Option<String>,
+  timetoken: Option<String>,
+}
+
+impl JsonError {
+  fn new(err: serde_json::Error, raw: &str) -> Self {
+    Self {
+      err,
+      raw: Some(raw.to_owned()),
+      channel: None,
+      timetoken: None,
+    }
+  }
+
+  // For a message received on a subscription.
+  fn arrived(self, channel: Option<String>, timetoken: Option<String>) -> Self {
+    Self {
+      channel,
+      timetoken,
+      ..self
+    }
+  }
+
+  /// The JSON that could not be decoded, if it was kept.
+  pub fn raw(&self) -> Option<&str> {
+    self.raw.as_deref()
+  }
+
+  /// The channel the message arrived on, for messages received on a subscription.
+  pub fn channel(&self) -> Option<&str> {
+    self.channel.as_deref()
+  }
+
+  /// The timetoken the message arrived with, for messages received on a subscription.
+  pub fn timetoken(&self) -> Option<&str> {
+    self.timetoken.as_deref()
+  }
 }
 
 impl std::fmt::Display for JsonError {
@@ -0,0 +1,329 @@
+use futures::task::Task;
+use futures::Async;
+use std::sync::Mutex;
+
+use zugzug_sys::callback::*;
+
+use crate::{ChannelConfig, ClientError};
+
+/// Configures the pool of contexts a `Client` reuses for publishing.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Hash)]
+pub struct PoolConfig {
+  /// The most contexts to keep, and so the most publishes in flight at once.
+  pub size: usize,
+  /// What a publish does when every context is in use.
+  pub when_exhausted: WhenExhausted,
+}
+
+impl PoolConfig {
+  pub(crate) fn validate(&self) -> Result<(), ClientError> {
+    if self.size == 0 {
+      Err(ClientError::InvalidOptions {
+        reason: "a publish pool must hold at least one context",
+      })
+    } else {
+      Ok(())
+    }
+  }
+}
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Hash)]
+pub enum WhenExhausted {
+  /// Wait for a context to be returned.
+  Queue,
+  /// Fail with `ClientError::PoolExhausted`.
+  Error,
+}
+
+/// A snapshot of a context pool.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct PoolStats {
+  pub size: usize,
+  /// Contexts allocated so far, up to `size`.
+  pub created: usize,
+  /// Contexts waiting to be checked out.
+  pub idle: usize,
+  /// Contexts checked out by publishes in flight.
+  pub in_use: usize,
+  /// Publishes waiting for a context.
+  pub queued: usize,
+  /// How many times a publish found every context in use.
+  pub exhausted: u64,
+}
+
+// Generic over the context so that the bookkeeping can be tested without c-core.
+#[derive(Debug)]
+struct PoolState<C> {
+  idle: Vec<C>,
+  created: usize,
+  // Keyed by waiter ids, so that a publish polled again while queued replaces its task rather than queueing twice.
+  waiting: Vec<(u64, Task)>,
+  next_waiter: u64,
+  exhausted: u64,
+}
+
+impl<C> PoolState<C> {
+  fn new() -> Self {
+    Self {
+      idle: Vec::new(),
+      created: 0,
+      waiting: Vec::new(),
+      next_waiter: 0,
+      exhausted: 0,
+    }
+  }
+
+  // `waiter` identifies the publish across polls: it is set the first time the publish finds the pool exhausted.
+  fn checkout(
+    &mut self,
+    config: &PoolConfig,
+    alloc: impl FnOnce() -> C,
+    current: impl FnOnce() -> Task,
+    waiter: &mut Option<u64>,
+  ) -> Result<Async<C>, ClientError> {
+    if let Some(ctx) = self.idle.pop() {
+      self.unqueue(*waiter);
+      return Ok(Async::Ready(ctx));
+    }
+    if self.created < config.size {
+      self.created += 1;
+      self.unqueue(*waiter);
+      return Ok(Async::Ready(alloc()));
+    }
+
+    let id = match *waiter {
+      Some(id) => id,
+      None => {
+        self.exhausted += 1;
+        self.next_waiter += 1;
+        *waiter = Some(self.next_waiter);
+        self.next_waiter
+      }
+    };
+    match config.when_exhausted {
+      WhenExhausted::Queue => {
+        self.unqueue(Some(id));
+        self.waiting.push((id, current()));
+        Ok(Async::NotReady)
+      }
+      WhenExhausted::Error => Err(ClientError::PoolExhausted),
+    }
+  }
+
+  // For a publish that got a context or gave up.
+  fn unqueue(&mut self, waiter: Option<u64>) {
+    if let Some(waiter) = waiter {
+      self.waiting.retain(|&(id, _)| id != waiter);
+    }
+  }
+
+  // Waiters may have given up since they queued, so wake all of them to race for the context.
+  fn wake_waiting(&mut self) {
+    for (_, task) in self.waiting.drain(..) {
+      task.notify();
+    }
+  }
+
+  fn release(&mut self, ctx: C) {
+    self.idle.push(ctx);
+    self.wake_waiting();
+  }
+
+  // For a context that had to be leaked rather than returned, so that another can be allocated in its place.
+  fn discard(&mut self) {
+    self.created -= 1;
+    self.wake_waiting();
+  }
+
+  fn stats(&self, config: &PoolConfig) -> PoolStats {
+    PoolStats {
+      size: config.size,
+      created: self.created,
+      idle: self.idle.len(),
+      in_use: self.created - self.idle.len(),
+      queued: self.waiting.len(),
+      exhausted: self.exhausted,
+    }
+  }
+}
+
+// Initialized contexts that keep their connection to PubNub open between publishes.
+#[derive(Debug)]
+pub(crate) struct ContextPool {
+  config: PoolConfig,
+  state: Mutex<PoolState<*mut pubnub_t>>,
+  // We pass refs of these to C land.  We keep them around here so they will not be freed until the contexts are.
+  keys: ChannelConfig,
+}
+
+// The contexts are only used by one transaction at a time, and c-core contexts may move between threads.
+// See https://www.pubnub.com/docs/posix-c/api-reference-configuration#Thread_safety
+unsafe impl Send for ContextPool {}
+unsafe impl Sync for ContextPool {}
+
+impl ContextPool {
+  pub(crate) fn new(config: PoolConfig, keys: ChannelConfig) -> Self {
+    Self {
+      config,
+      state: Mutex::new(PoolState::new()),
+      keys,
+    }
+  }
+
+  // Must be called from within a task, in case the publish has to queue.
+  pub(crate) fn checkout(&self, waiter: &mut Option<u64>) -> Result<Async<*mut pubnub_t>, ClientError> {
+    let keys = &self.keys;
+    self.state.lock().unwrap().checkout(
+      &self.config,
+      || unsafe {
+        let ctx = pubnub_alloc();
+        pubnub_init(ctx, keys.publish_key.as_ptr(), keys.subscribe_key.as_ptr());
+        pubnub_set_uuid(ctx, keys.client_uuid.as_ptr());
+        pubnub_set_auth(ctx, keys.auth_key.as_ptr());
+        ctx
+      },
+      futures::task::current,
+      waiter,
+    )
+  }
+
+  pub(crate) fn unqueue(&self, waiter: Option<u64>) {
+    self.state.lock().unwrap().unqueue(waiter);
+  }
+
+  // Only for contexts with no transaction in flight.
+  pub(crate) fn release(&self, ctx: *mut pubnub_t) {
+    self.state.lock().unwrap().release(ctx);
+  }
+
+  // For a context that may still be in use by c-core, and so can neither be freed nor reused.
+  pub(crate) fn discard(&self) {
+    self.state.lock().unwrap().discard();
+  }
+
+  pub(crate) fn stats(&self) -> PoolStats {
+    self.state.lock().unwrap().stats(&self.config)
+  }
+}
+
+impl Drop for ContextPool {
+  fn drop(&mut self) {
+    // Every checked out context holds a reference to the pool, so all of them are idle by now.
+    for ctx in self.state.get_mut().unwrap().idle.drain(..) {
+      unsafe { pubnub_free(ctx) };
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::future::{lazy, Future};
+
+  fn config(size: usize, when_exhausted: WhenExhausted) -> PoolConfig {
+    PoolConfig { size, when_exhausted }
+  }
+
+  #[test]
+  fn reuses_released_contexts() {
+    let config = config(2, WhenExhausted::Error);
+    let mut pool = PoolState::new();
+    let mut next = 0;
+    let mut checkout = |pool: &mut PoolState<u32>| {
+      pool.checkout(
+        &config,
+        || {
+          next += 1;
+          next
+        },
+        futures::task::current,
+        &mut None,
+      )
+    };
+
+    assert_eq!(checkout(&mut pool).unwrap(), Async::Ready(1));
+    assert_eq!(checkout(&mut pool).unwrap(), Async::Ready(2));
+    assert!(checkout(&mut pool).is_err());
+
+    pool.release(1);
+
+    assert_eq!(checkout(&mut pool).unwrap(), Async::Ready(1));
+    assert_eq!(
+      pool.stats(&config),
+      PoolStats {
+        size: 2,
+        created: 2,
+        idle: 0,
+        in_use: 2,
+        queued: 0,
+        exhausted: 1,
+      }
+    );
+  }
+
+  #[test]
+  fn queues_when_exhausted() {
+    let config = config(1, WhenExhausted::Queue);
+    let mut pool = PoolState::new();
+
+    lazy(|| -> Result<(), ()> {
+      assert_eq!(
+        pool.checkout(&config, || 1, futures::task::current, &mut None).unwrap(),
+        Async::Ready(1)
+      );
+      assert_eq!(
+        pool.checkout(&config, || 2, futures::task::current, &mut None).unwrap(),
+        Async::NotReady
+      );
+      assert_eq!(pool.stats(&config).queued, 1);
+
+      pool.discard();
+
+      assert_eq!(pool.stats(&config).queued, 0);
+      assert_eq!(
+        pool.checkout(&config, || 3, futures::task::current, &mut None).unwrap(),
+        Async::Ready(3)
+      );
+      Ok(())
+    })
+    .wait()
+    .unwrap();
+  }
+
+  #[test]
+  fn counts_a_queued_publish_once() {
+    let config = config(1, WhenExhausted::Queue);
+    let mut pool = PoolState::new();
+    let mut waiter = None;
+
+    lazy(|| -> Result<(), ()> {
+      assert_eq!(
+        pool.checkout(&config, || 1, futures::task::current, &mut None).unwrap(),
+        Async::Ready(1)
+      );
+      for _ in 0..3 {
+        assert_eq!(
+          pool
+            .checkout(&config, || 2, futures::task::current, &mut waiter)
+            .unwrap(),
+          Async::NotReady
+        );
+      }
+      assert_eq!(pool.stats(&config).queued, 1);
+      assert_eq!(pool.stats(&config).exhausted, 1);
+
+      pool.unqueue(waiter);
+
+      assert_eq!(pool.stats(&config).queued, 0);
+      Ok(())
+    })
+    .wait()
+    .unwrap();
+  }
+
+  #[test]
+  fn rejects_an_empty_pool() {
+    assert!(config(1, WhenExhausted::Queue).validate().is_ok());
+    assert!(config(0, WhenExhausted::Queue).validate().is_err());
+  }
+}
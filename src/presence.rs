@@ -0,0 +1,381 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+
+use zugzug_sys::callback::*;
+
+use crate::{as_ptr_or_null, join_names, parse_json, Client, Subscription, TransactionFuture};
+
+// Appended to a channel's name for the channel PubNub publishes its presence events on.
+const PRESENCE_SUFFIX: &str = "-pnpres";
+
+// PubNub publishes to these itself, in plain JSON, whatever codec and cipher key the client has.
+pub(crate) fn is_presence_channel(channel: &str) -> bool {
+  channel.ends_with(PRESENCE_SUFFIX)
+}
+
+/// A client present on a channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Occupant {
+  pub uuid: String,
+  /// The state the client set on the channel, if it set any.
+  pub state: Option<serde_json::Value>,
+}
+
+/// Who is present on a single channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelOccupancy {
+  pub occupancy: u32,
+  pub occupants: Vec<Occupant>,
+}
+
+/// The result of a here_now query, keyed by channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HereNow {
+  pub total_occupancy: u32,
+  pub channels: HashMap<String, ChannelOccupancy>,
+}
+
+/// The channels a client is present on.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct WhereNow {
+  pub channels: Vec<String>,
+}
+
+// Occupants are listed as bare UUIDs, or as objects when state is requested.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OccupantResponse {
+  Uuid(String),
+  WithState {
+    uuid: String,
+    state: Option<serde_json::Value>,
+  },
+}
+
+impl From<OccupantResponse> for Occupant {
+  fn from(res: OccupantResponse) -> Self {
+    match res {
+      OccupantResponse::Uuid(uuid) => Occupant { uuid, state: None },
+      OccupantResponse::WithState { uuid, state } => Occupant { uuid, state },
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct ChannelOccupancyResponse {
+  occupancy: u32,
+  #[serde(default)]
+  uuids: Vec<OccupantResponse>,
+}
+
+impl From<ChannelOccupancyResponse> for ChannelOccupancy {
+  fn from(res: ChannelOccupancyResponse) -> Self {
+    ChannelOccupancy {
+      occupancy: res.occupancy,
+      occupants: res.uuids.into_iter().map(Occupant::from).collect(),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct MultiChannelResponse {
+  total_occupancy: u32,
+  #[serde(default)]
+  channels: HashMap<String, ChannelOccupancyResponse>,
+}
+
+// A query for exactly one channel gets a flat response, anything else gets the per-channel `payload`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HereNowResponse {
+  Multi { payload: MultiChannelResponse },
+  Single(ChannelOccupancyResponse),
+}
+
+impl HereNowResponse {
+  fn into_here_now(self, channel: &str) -> HereNow {
+    match self {
+      HereNowResponse::Multi { payload } => HereNow {
+        total_occupancy: payload.total_occupancy,
+        channels: payload
+          .channels
+          .into_iter()
+          .map(|(name, occupancy)| (name, occupancy.into()))
+          .collect(),
+      },
+      HereNowResponse::Single(occupancy) => HereNow {
+        total_occupancy: occupancy.occupancy,
+        channels: vec![(channel.to_owned(), occupancy.into())].into_iter().collect(),
+      },
+    }
+  }
+}
+
+/// An event published on a channel's `-pnpres` companion channel.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum PresenceEvent {
+  Join {
+    uuid: String,
+    occupancy: u32,
+    timestamp: u64,
+    /// The state the client joined with, if any.
+    #[serde(rename = "data", default)]
+    state: Option<serde_json::Value>,
+  },
+  Leave {
+    uuid: String,
+    occupancy: u32,
+    timestamp: u64,
+  },
+  Timeout {
+    uuid: String,
+    occupancy: u32,
+    timestamp: u64,
+  },
+  StateChange {
+    uuid: String,
+    occupancy: u32,
+    timestamp: u64,
+    #[serde(rename = "data")]
+    state: serde_json::Value,
+  },
+  /// Sent instead of individual events once a channel's occupancy passes the announce threshold.  The UUIDs that
+  /// joined, left or timed out since the last interval are listed when PubNub includes them.
+  Interval {
+    occupancy: u32,
+    timestamp: u64,
+    #[serde(default)]
+    join: Vec<String>,
+    #[serde(default)]
+    leave: Vec<String>,
+    #[serde(default)]
+    timeout: Vec<String>,
+  },
+}
+
+#[derive(Deserialize)]
+struct MultiStateResponse<S> {
+  channels: HashMap<String, S>,
+}
+
+// Like here_now, a query for exactly one channel gets a flat response.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StateResponse<S> {
+  Single { channel: String, payload: S },
+  Multi { payload: MultiStateResponse<S> },
+}
+
+impl<S> StateResponse<S> {
+  fn into_states(self) -> HashMap<String, S> {
+    match self {
+      StateResponse::Single { channel, payload } => vec![(channel, payload)].into_iter().collect(),
+      StateResponse::Multi { payload } => payload.channels,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct WhereNowResponse {
+  payload: WhereNow,
+}
+
+impl Client {
+  /// Lists the clients present on `channels` and the channels in `groups`, along with their state.
+  pub fn here_now(&self, channels: &[&str], groups: &[&str]) -> TransactionFuture<HereNow> {
+    let channel = channels.join(",");
+
+    TransactionFuture::new(
+      self.channel_config(join_names(channels), join_names(groups)),
+      pubnub_trans_PBTT_HERENOW,
+      Box::new(|ctx, config| unsafe {
+        let mut opts = pubnub_here_now_defopts();
+        opts.channel_group = as_ptr_or_null(&config.group);
+        opts.state = true;
+        pubnub_here_now_ex(ctx, as_ptr_or_null(&config.channel), opts)
+      }),
+      Box::new(move |pb| unsafe { parse_json::<HereNowResponse>(pb) }.map(|res| res.into_here_now(&channel))),
+    )
+  }
+
+  /// Lists the clients present on every channel of the subscribe key, along with their state.
+  pub fn global_here_now(&self) -> TransactionFuture<HereNow> {
+    TransactionFuture::new(
+      self.channel_config(None, None),
+      pubnub_trans_PBTT_GLOBAL_HERENOW,
+      Box::new(|ctx, _| unsafe {
+        let mut opts = pubnub_here_now_defopts();
+        opts.state = true;
+        pubnub_global_here_now_ex(ctx, opts)
+      }),
+      Box::new(|pb| unsafe { parse_json::<HereNowResponse>(pb) }.map(|res| res.into_here_now(""))),
+    )
+  }
+
+  /// Sets this client's state on `channels` and the channels in `groups`.  Fails without sending if `state` can't be
+  /// serialized to JSON.
+  pub fn set_state<S: Serialize>(&self, channels: &[&str], groups: &[&str], state: S) -> TransactionFuture<()> {
+    let config = self.channel_config(join_names(channels), join_names(groups));
+    let state_c = match serde_json::to_string(&state) {
+      Ok(state_string) => CString::new(state_string).expect("JSON doesn't include nul"),
+      Err(e) => return TransactionFuture::failed(config, pubnub_trans_PBTT_SET_STATE, e.into()),
+    };
+
+    TransactionFuture::new(
+      config,
+      pubnub_trans_PBTT_SET_STATE,
+      Box::new(move |ctx, config| unsafe {
+        pubnub_set_state(
+          ctx,
+          as_ptr_or_null(&config.channel),
+          as_ptr_or_null(&config.group),
+          config.client_uuid.as_ptr(),
+          state_c.as_ptr(),
+        )
+      }),
+      Box::new(|_| Ok(())),
+    )
+  }
+
+  /// Gets the state the client with `uuid` has set on each of `channels`.
+  pub fn get_state<S: DeserializeOwned>(&self, uuid: &str, channels: &[&str]) -> TransactionFuture<HashMap<String, S>> {
+    let uuid_c = CString::new(uuid).expect("UTF-8 doesn't include nul");
+
+    TransactionFuture::new(
+      self.channel_config(join_names(channels), None),
+      pubnub_trans_PBTT_STATE_GET,
+      Box::new(move |ctx, config| unsafe {
+        pubnub_state_get(ctx, as_ptr_or_null(&config.channel), std::ptr::null(), uuid_c.as_ptr())
+      }),
+      Box::new(|pb| unsafe { parse_json::<StateResponse<S>>(pb) }.map(StateResponse::into_states)),
+    )
+  }
+
+  /// Subscribes to the presence events of `channel`.  PubNub sends these as plain JSON, so the client's codec and
+  /// cipher key don't apply to them.
+  pub fn presence_events(&self, channel: &str) -> Subscription<PresenceEvent> {
+    self.subscribe(Some(&format!("{}{}", channel, PRESENCE_SUFFIX)), None)
+  }
+
+  /// Lists the channels the client with `uuid` is present on.
+  pub fn where_now(&self, uuid: &str) -> TransactionFuture<WhereNow> {
+    let uuid_c = CString::new(uuid).expect("UTF-8 doesn't include nul");
+
+    TransactionFuture::new(
+      self.channel_config(None, None),
+      pubnub_trans_PBTT_WHERENOW,
+      Box::new(move |ctx, _| unsafe { pubnub_where_now(ctx, uuid_c.as_ptr()) }),
+      Box::new(|pb| unsafe { parse_json::<WhereNowResponse>(pb) }.map(|res| res.payload)),
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::ClientError;
+  use futures::Future;
+
+  #[test]
+  fn parses_single_channel_here_now() {
+    let json = r#"{"status":200,"message":"OK","occupancy":2,"uuids":["a",{"uuid":"b","state":{"battery":80}}],"service":"Presence"}"#;
+    let here_now = serde_json::from_str::<HereNowResponse>(json)
+      .unwrap()
+      .into_here_now("c");
+
+    assert_eq!(here_now.total_occupancy, 2);
+    assert_eq!(
+      here_now.channels["c"].occupants,
+      vec![
+        Occupant {
+          uuid: "a".to_owned(),
+          state: None,
+        },
+        Occupant {
+          uuid: "b".to_owned(),
+          state: Some(serde_json::json!({"battery": 80})),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn parses_multi_channel_here_now() {
+    let json = r#"{"status":200,"message":"OK","payload":{"channels":{"c1":{"occupancy":1,"uuids":["a"]},"c2":{"occupancy":0,"uuids":[]}},"total_channels":2,"total_occupancy":1},"service":"Presence"}"#;
+    let here_now = serde_json::from_str::<HereNowResponse>(json)
+      .unwrap()
+      .into_here_now("c1,c2");
+
+    assert_eq!(here_now.total_occupancy, 1);
+    assert_eq!(here_now.channels.len(), 2);
+    assert_eq!(here_now.channels["c1"].occupancy, 1);
+  }
+
+  #[test]
+  fn parses_presence_events() {
+    let join = r#"{"action":"join","uuid":"a","occupancy":1,"timestamp":1345546797}"#;
+    let state_change =
+      r#"{"action":"state-change","uuid":"a","occupancy":1,"timestamp":1345546798,"data":{"fw":"1.2"}}"#;
+    let interval = r#"{"action":"interval","occupancy":3,"timestamp":1345546799,"join":["b","c"]}"#;
+
+    assert_eq!(
+      serde_json::from_str::<PresenceEvent>(join).unwrap(),
+      PresenceEvent::Join {
+        uuid: "a".to_owned(),
+        occupancy: 1,
+        timestamp: 1_345_546_797,
+        state: None,
+      }
+    );
+    assert_eq!(
+      serde_json::from_str::<PresenceEvent>(state_change).unwrap(),
+      PresenceEvent::StateChange {
+        uuid: "a".to_owned(),
+        occupancy: 1,
+        timestamp: 1_345_546_798,
+        state: serde_json::json!({"fw": "1.2"}),
+      }
+    );
+    assert_eq!(
+      serde_json::from_str::<PresenceEvent>(interval).unwrap(),
+      PresenceEvent::Interval {
+        occupancy: 3,
+        timestamp: 1_345_546_799,
+        join: vec!["b".to_owned(), "c".to_owned()],
+        leave: vec![],
+        timeout: vec![],
+      }
+    );
+  }
+
+  #[test]
+  fn fails_to_set_state_that_is_not_json() {
+    let state = vec![((1u8, 2u8), 3u8)].into_iter().collect::<HashMap<_, _>>();
+
+    assert!(matches!(
+      Client::for_test().set_state(&["c"], &[], state).wait(),
+      Err(ClientError::ParseError(_))
+    ));
+  }
+
+  #[test]
+  fn parses_state() {
+    let single =
+      r#"{"status":200,"uuid":"a","service":"Presence","message":"OK","payload":{"battery":80},"channel":"c"}"#;
+    let multi = r#"{"status":200,"message":"OK","payload":{"uuid":"a","channels":{"c1":{"battery":80},"c2":{"battery":70}}},"service":"Presence"}"#;
+
+    let single = serde_json::from_str::<StateResponse<serde_json::Value>>(single)
+      .unwrap()
+      .into_states();
+    let multi = serde_json::from_str::<StateResponse<serde_json::Value>>(multi)
+      .unwrap()
+      .into_states();
+
+    assert_eq!(single["c"], serde_json::json!({"battery": 80}));
+    assert_eq!(multi.len(), 2);
+    assert_eq!(multi["c2"], serde_json::json!({"battery": 70}));
+  }
+}
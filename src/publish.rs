@@ -0,0 +1,250 @@
+use serde::Serialize;
+use std::ffi::CString;
+
+use zugzug_sys::callback::*;
+
+use crate::crypto::Cipher;
+use crate::{as_ptr_or_null, encode_message, Client, ClientError, PublishFuture};
+
+// PubNub rejects signals with a larger payload.
+const MAX_SIGNAL_SIZE: usize = 64;
+
+/// How a publish is sent to PubNub.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub enum PublishMethod {
+  /// The message goes in the URL, which limits its size.
+  Get,
+  /// The message goes in the body, for larger messages.
+  Post,
+}
+
+/// Options for `Client::publish_with`, built up from `PublishOptions::new()`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct PublishOptions {
+  store: Option<bool>,
+  ttl: Option<u32>,
+  meta: Option<serde_json::Value>,
+  replicate: bool,
+  method: PublishMethod,
+  cipher_key: Option<String>,
+}
+
+impl Default for PublishOptions {
+  fn default() -> Self {
+    Self {
+      store: None,
+      ttl: None,
+      meta: None,
+      replicate: true,
+      method: PublishMethod::Get,
+      cipher_key: None,
+    }
+  }
+}
+
+impl PublishOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Whether to keep the message in history.  Defaults to the key's storage setting.
+  pub fn store(self, store: bool) -> Self {
+    Self {
+      store: Some(store),
+      ..self
+    }
+  }
+
+  /// How many hours to keep the message in history, instead of the key's retention period.
+  pub fn ttl(self, hours: u32) -> Self {
+    Self {
+      ttl: Some(hours),
+      ..self
+    }
+  }
+
+  /// A JSON object to send alongside the message, which subscribers can filter on.  See `Envelope::meta`.
+  pub fn meta(self, meta: serde_json::Value) -> Self {
+    Self {
+      meta: Some(meta),
+      ..self
+    }
+  }
+
+  /// Whether to replicate the message to other PubNub data centers.  Defaults to `true`.
+  pub fn replicate(self, replicate: bool) -> Self {
+    Self { replicate, ..self }
+  }
+
+  /// Defaults to `PublishMethod::Get`.
+  pub fn method(self, method: PublishMethod) -> Self {
+    Self { method, ..self }
+  }
+
+  /// Encrypt the message with this key instead of `ClientConfig::cipher_key`.
+  pub fn cipher_key(self, cipher_key: impl Into<String>) -> Self {
+    Self {
+      cipher_key: Some(cipher_key.into()),
+      ..self
+    }
+  }
+
+  // Catches options PubNub would reject or silently ignore.
+  fn validate(&self) -> Result<(), ClientError> {
+    let reason = if self.ttl.is_some() && self.store == Some(false) {
+      "a TTL only applies to stored messages"
+    } else if self.ttl == Some(0) {
+      "a TTL must be at least an hour"
+    } else if matches!(self.meta, Some(ref meta) if !meta.is_object()) {
+      "meta must be a JSON object"
+    } else if self.cipher_key.as_deref() == Some("") {
+      "a cipher key must not be empty"
+    } else {
+      return Ok(());
+    };
+    Err(ClientError::InvalidOptions { reason })
+  }
+
+  // Fills in c-core's options.  `meta` must outlive the publish.
+  pub(crate) unsafe fn to_c(&self, meta: &Option<CString>) -> pubnub_publish_options {
+    let mut options = pubnub_publish_defopts();
+    if let Some(store) = self.store {
+      options.store = store;
+    }
+    if let Some(ttl) = self.ttl {
+      options.ttl = ttl;
+    }
+    options.meta = as_ptr_or_null(meta);
+    options.replicate = self.replicate;
+    options.method = match self.method {
+      PublishMethod::Get => pubnub_method_pubnubSendViaGET,
+      PublishMethod::Post => pubnub_method_pubnubSendViaPOST,
+    };
+    options
+  }
+
+  pub(crate) fn meta_c(&self) -> Option<CString> {
+    self
+      .meta
+      .as_ref()
+      .map(|meta| CString::new(meta.to_string()).expect("JSON doesn't include nul"))
+  }
+}
+
+impl Client {
+  /// Like `publish`, but with `PublishOptions`.  Fails without publishing if the options are invalid.
+  pub fn publish_with<T: Serialize>(&self, channel: &str, body: T, options: PublishOptions) -> PublishFuture {
+    let channel_c = CString::new(channel).expect("UTF-8 doesn't include nul");
+    let config = self.channel_config(Some(channel_c), None);
+    if let Err(e) = options.validate() {
+      return PublishFuture::failed(config, pubnub_trans_PBTT_PUBLISH, e);
+    }
+
+    let cipher = options.cipher_key.as_deref().map(Cipher::new);
+    PublishFuture::publish(
+      config,
+      self.publish_pool.clone(),
+      &*self.codec,
+      cipher.as_ref().or(self.cipher.as_ref()),
+      body,
+      options,
+    )
+  }
+
+  /// Publishes a message to the subscribers of `channel` only: it is neither stored in history nor replicated to other
+  /// PubNub data centers.
+  pub fn fire<T: Serialize>(&self, channel: &str, body: T) -> PublishFuture {
+    self.publish_with(channel, body, PublishOptions::new().store(false).replicate(false))
+  }
+
+  /// Sends a signal, a cheaper kind of message for frequent, small updates such as typing indicators.  Signals are
+  /// never stored or encrypted, and fail without sending if their encoded payload is over 64 bytes.  Subscribers tell
+  /// them apart by `Envelope::message_type`.
+  pub fn signal<T: Serialize>(&self, channel: &str, body: T) -> PublishFuture {
+    let channel_c = CString::new(channel).expect("UTF-8 doesn't include nul");
+    let config = self.channel_config(Some(channel_c), None);
+    let msg_string = match encode_message(&*self.codec, body) {
+      Ok(msg_string) => msg_string,
+      Err(e) => return PublishFuture::failed(config, pubnub_trans_PBTT_SIGNAL, e.into()),
+    };
+    if let Err(e) = check_signal_size(&msg_string) {
+      return PublishFuture::failed(config, pubnub_trans_PBTT_SIGNAL, e);
+    }
+    let msg_c = CString::new(msg_string).expect("encode_message rejects nul");
+
+    PublishFuture::pooled(
+      config,
+      self.publish_pool.clone(),
+      pubnub_trans_PBTT_SIGNAL,
+      Box::new(move |ctx, config| unsafe { pubnub_signal(ctx, as_ptr_or_null(&config.channel), msg_c.as_ptr()) }),
+      Box::new(|_| Ok(())),
+    )
+  }
+}
+
+fn check_signal_size(message: &str) -> Result<(), ClientError> {
+  if message.len() > MAX_SIGNAL_SIZE {
+    Err(ClientError::TooLarge {
+      size: message.len(),
+      max: MAX_SIGNAL_SIZE,
+    })
+  } else {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::Codec;
+  use serde_json::{json, Value};
+
+  // Encodes every message as a JSON string holding a nul, which no built-in codec would.
+  #[derive(Debug)]
+  struct Nul;
+
+  impl Codec for Nul {
+    fn encode(&self, _: Value) -> Result<String, serde_json::Error> {
+      Ok("\"a\0b\"".to_owned())
+    }
+
+    fn decode(&self, message: &str) -> Result<Value, serde_json::Error> {
+      serde_json::from_str(message)
+    }
+  }
+
+  fn reason(options: PublishOptions) -> Option<&'static str> {
+    match options.validate() {
+      Err(ClientError::InvalidOptions { reason }) => Some(reason),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn validates() {
+    assert_eq!(reason(PublishOptions::new()), None);
+    assert_eq!(
+      reason(PublishOptions::new().store(true).ttl(24).meta(json!({"room": "a"}))),
+      None
+    );
+    assert!(reason(PublishOptions::new().store(false).ttl(24)).is_some());
+    assert!(reason(PublishOptions::new().ttl(0)).is_some());
+    assert!(reason(PublishOptions::new().meta(json!([1, 2]))).is_some());
+    assert!(reason(PublishOptions::new().cipher_key("")).is_some());
+  }
+
+  #[test]
+  fn rejects_messages_encoded_with_nul() {
+    assert!(encode_message(&Nul, 1).is_err());
+    assert_eq!(encode_message(&crate::Json, "a\0b").unwrap(), r#""a\u0000b""#);
+  }
+
+  #[test]
+  fn limits_signal_size() {
+    assert!(check_signal_size(&format!("\"{}\"", "a".repeat(62))).is_ok());
+    assert!(matches!(
+      check_signal_size(&format!("\"{}\"", "a".repeat(63))),
+      Err(ClientError::TooLarge { size: 65, max: 64 })
+    ));
+  }
+}
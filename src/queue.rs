@@ -0,0 +1,208 @@
+use futures::task::AtomicTask;
+use futures::Async;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// What a subscription does with a message that arrives while its buffer is full.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Hash)]
+pub enum OverflowPolicy {
+  /// Drop the message that just arrived.
+  DropNewest,
+  /// Drop the oldest buffered message to make room.
+  DropOldest,
+  /// Hold up c-core's callback thread until there is room.  This delays every other subscription and transaction
+  /// callback too, so use with care.
+  Block,
+  /// Never drop anything, but print a warning whenever the buffer grows past `high_water_mark`.
+  Unbounded { high_water_mark: usize },
+}
+
+struct QueueState<T> {
+  items: VecDeque<T>,
+  // Whether the sending or receiving end has gone away.
+  sender_closed: bool,
+  receiver_closed: bool,
+}
+
+// The buffer between a subscription's callback and its stream.  Unlike an mpsc channel, the sending side can drop
+// the oldest message or wait for room.
+struct Queue<T> {
+  state: Mutex<QueueState<T>>,
+  space: Condvar,
+  task: AtomicTask,
+  capacity: usize,
+  overflow: OverflowPolicy,
+  dropped: AtomicU64,
+}
+
+pub(crate) fn queue<T>(capacity: usize, overflow: OverflowPolicy) -> (QueueSender<T>, QueueReceiver<T>) {
+  let queue = Arc::new(Queue {
+    state: Mutex::new(QueueState {
+      items: VecDeque::new(),
+      sender_closed: false,
+      receiver_closed: false,
+    }),
+    space: Condvar::new(),
+    task: AtomicTask::new(),
+    capacity: capacity.max(1),
+    overflow,
+    dropped: AtomicU64::new(0),
+  });
+  (QueueSender { queue: queue.clone() }, QueueReceiver { queue })
+}
+
+pub(crate) struct QueueSender<T> {
+  queue: Arc<Queue<T>>,
+}
+
+impl<T> QueueSender<T> {
+  pub(crate) fn send(&self, item: T) {
+    let queue = &*self.queue;
+    let mut state = queue.state.lock().unwrap();
+    if state.receiver_closed {
+      return;
+    }
+
+    if state.items.len() >= queue.capacity {
+      match queue.overflow {
+        OverflowPolicy::DropNewest => {
+          queue.dropped.fetch_add(1, Ordering::Relaxed);
+          return;
+        }
+        OverflowPolicy::DropOldest => {
+          state.items.pop_front();
+          queue.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        OverflowPolicy::Block => {
+          state = queue
+            .space
+            .wait_while(state, |s| s.items.len() >= queue.capacity && !s.receiver_closed)
+            .unwrap();
+          if state.receiver_closed {
+            return;
+          }
+        }
+        OverflowPolicy::Unbounded { .. } => {}
+      }
+    }
+    if let OverflowPolicy::Unbounded { high_water_mark } = queue.overflow {
+      if state.items.len() == high_water_mark {
+        eprintln!(
+          "Subscription buffer passed {} messages, is the stream being polled?",
+          high_water_mark
+        );
+      }
+    }
+
+    state.items.push_back(item);
+    queue.task.notify();
+  }
+}
+
+impl<T> Drop for QueueSender<T> {
+  fn drop(&mut self) {
+    self.queue.state.lock().unwrap().sender_closed = true;
+    self.queue.task.notify();
+  }
+}
+
+pub(crate) struct QueueReceiver<T> {
+  queue: Arc<Queue<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+  // Like `Stream::poll`, so must be called from within a task.
+  pub(crate) fn poll(&mut self) -> Async<Option<T>> {
+    let queue = &*self.queue;
+    let mut state = queue.state.lock().unwrap();
+    match state.items.pop_front() {
+      Some(item) => {
+        queue.space.notify_one();
+        Async::Ready(Some(item))
+      }
+      None if state.sender_closed => Async::Ready(None),
+      None => {
+        // Registered with the lock held, so a send can't slip in between.
+        queue.task.register();
+        Async::NotReady
+      }
+    }
+  }
+
+  pub(crate) fn dropped(&self) -> u64 {
+    self.queue.dropped.load(Ordering::Relaxed)
+  }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+  fn drop(&mut self) {
+    // Otherwise a blocked callback would wait forever, and with it the subscription's teardown.
+    self.queue.state.lock().unwrap().receiver_closed = true;
+    self.queue.space.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::future::{lazy, Future};
+
+  fn drain(rx: &mut QueueReceiver<u32>) -> Vec<u32> {
+    lazy(|| -> Result<_, ()> {
+      let mut items = Vec::new();
+      while let Async::Ready(Some(item)) = rx.poll() {
+        items.push(item);
+      }
+      Ok(items)
+    })
+    .wait()
+    .unwrap()
+  }
+
+  #[test]
+  fn drops_newest() {
+    let (tx, mut rx) = queue(2, OverflowPolicy::DropNewest);
+    (1..=4).for_each(|i| tx.send(i));
+
+    assert_eq!(drain(&mut rx), vec![1, 2]);
+    assert_eq!(rx.dropped(), 2);
+  }
+
+  #[test]
+  fn drops_oldest() {
+    let (tx, mut rx) = queue(2, OverflowPolicy::DropOldest);
+    (1..=4).for_each(|i| tx.send(i));
+
+    assert_eq!(drain(&mut rx), vec![3, 4]);
+    assert_eq!(rx.dropped(), 2);
+  }
+
+  #[test]
+  fn blocks_until_there_is_room() {
+    let (tx, mut rx) = queue(1, OverflowPolicy::Block);
+    let sender = std::thread::spawn(move || (1..=3).for_each(|i| tx.send(i)));
+
+    let mut items = Vec::new();
+    while items.len() < 3 {
+      items.extend(drain(&mut rx));
+    }
+    sender.join().unwrap();
+
+    assert_eq!(items, vec![1, 2, 3]);
+    assert_eq!(rx.dropped(), 0);
+  }
+
+  #[test]
+  fn ends_when_the_sender_is_gone() {
+    let (tx, mut rx) = queue(1, OverflowPolicy::Unbounded { high_water_mark: 1 });
+    (1..=3).for_each(|i| tx.send(i));
+    drop(tx);
+
+    assert_eq!(drain(&mut rx), vec![1, 2, 3]);
+    assert_eq!(
+      lazy(|| -> Result<_, ()> { Ok(rx.poll()) }).wait().unwrap(),
+      Async::Ready(None)
+    );
+  }
+}
@@ -0,0 +1,229 @@
+use futures::stream::Stream;
+use futures::sync::mpsc::Receiver;
+use futures::Async;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::PubNubErrorKind;
+
+/// How a subscription recovers when a long-poll fails.  The delay before each attempt is jittered by up to half, so
+/// that clients that lost their connection together don't all come back at once.
+#[derive(Eq, PartialEq, Clone, Debug, Hash)]
+pub enum ReconnectPolicy {
+  /// Give up after the first failure.
+  None,
+  /// Wait `step` longer before each attempt than before the last, up to `max_delay`.
+  Linear {
+    step: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+  },
+  /// Double the wait before each attempt, starting from `initial_delay`, up to `max_delay`.
+  Exponential {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+  },
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    ReconnectPolicy::Exponential {
+      initial_delay: Duration::from_secs(1),
+      max_delay: Duration::from_secs(32),
+      max_attempts: None,
+    }
+  }
+}
+
+impl ReconnectPolicy {
+  // The delay before reconnect `attempt` (counting from 1), or `None` to give up.
+  pub(crate) fn delay(&self, attempt: u32) -> Option<Duration> {
+    let (delay, max_delay, max_attempts) = match *self {
+      ReconnectPolicy::None => return None,
+      ReconnectPolicy::Linear {
+        step,
+        max_delay,
+        max_attempts,
+      } => (step.checked_mul(attempt), max_delay, max_attempts),
+      ReconnectPolicy::Exponential {
+        initial_delay,
+        max_delay,
+        max_attempts,
+      } => (
+        2u32
+          .checked_pow(attempt - 1)
+          .and_then(|factor| initial_delay.checked_mul(factor)),
+        max_delay,
+        max_attempts,
+      ),
+    };
+
+    if matches!(max_attempts, Some(max) if attempt > max) {
+      None
+    } else {
+      Some(jitter(delay.map_or(max_delay, |delay| delay.min(max_delay))))
+    }
+  }
+}
+
+// Somewhere between half of `delay` and all of it, uniformly.
+fn jitter(delay: Duration) -> Duration {
+  rand::thread_rng().gen_range(delay / 2..=delay)
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Runs the reconnects of a client's subscriptions when they are due, on a thread of its own that is started with the
+// first reconnect and stops once the client and its subscriptions are gone.
+#[derive(Debug, Default)]
+pub(crate) struct ReconnectTimer {
+  tx: Mutex<Option<Sender<(Instant, Job)>>>,
+}
+
+impl ReconnectTimer {
+  pub(crate) fn schedule(&self, delay: Duration, job: impl FnOnce() + Send + 'static) {
+    let mut job = (Instant::now() + delay, Box::new(job) as Job);
+    let mut tx = self.tx.lock().unwrap();
+    // Only fails if the thread is gone, e.g. because a job panicked, in which case we start another.
+    while let Err(mpsc::SendError(unsent)) = tx.get_or_insert_with(spawn_timer).send(job) {
+      job = unsent;
+      *tx = None;
+    }
+  }
+}
+
+fn spawn_timer() -> Sender<(Instant, Job)> {
+  let (tx, rx) = mpsc::channel();
+  std::thread::Builder::new()
+    .name("zugzug-reconnect".to_owned())
+    .spawn(move || {
+      let mut due = BinaryHeap::new();
+      loop {
+        let next = match due.peek() {
+          Some(&Due(at, _)) => rx.recv_timeout(at.saturating_duration_since(Instant::now())),
+          None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match next {
+          Ok((at, job)) => due.push(Due(at, job)),
+          Err(RecvTimeoutError::Timeout) => {}
+          Err(RecvTimeoutError::Disconnected) => return,
+        }
+        while matches!(due.peek(), Some(&Due(at, _)) if at <= Instant::now()) {
+          (due.pop().unwrap().1)();
+        }
+      }
+    })
+    .expect("unable to spawn reconnect thread");
+  tx
+}
+
+// Ordered so that the `BinaryHeap` pops the job that is due first.
+struct Due(Instant, Job);
+
+impl PartialEq for Due {
+  fn eq(&self, other: &Self) -> bool {
+    self.0 == other.0
+  }
+}
+
+impl Eq for Due {}
+
+impl PartialOrd for Due {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Due {
+  fn cmp(&self, other: &Self) -> Ordering {
+    other.0.cmp(&self.0)
+  }
+}
+
+/// A change in a subscription's connection to PubNub.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum ConnectionStatus {
+  /// A long-poll succeeded, for the first time or after reconnecting.
+  Connected,
+  /// A long-poll failed, and another will be tried after `delay`.
+  Reconnecting {
+    attempt: u32,
+    delay: Duration,
+    error: PubNubErrorKind,
+  },
+  /// A long-poll failed and the reconnect policy gave up.  The error is also yielded by the subscription.
+  Disconnected { error: PubNubErrorKind },
+}
+
+/// The connection status events of a subscription, from `Subscription::connection_status`.  Events are dropped if
+/// this stream falls far behind.
+pub struct ConnectionStatusStream {
+  pub(crate) rx: Receiver<ConnectionStatus>,
+}
+
+impl Stream for ConnectionStatusStream {
+  type Item = ConnectionStatus;
+  type Error = ();
+
+  fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+    self.rx.poll()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn between(delay: Option<Duration>, min_ms: u64, max_ms: u64) -> bool {
+    let delay = delay.unwrap();
+    delay >= Duration::from_millis(min_ms) && delay <= Duration::from_millis(max_ms)
+  }
+
+  #[test]
+  fn backs_off_exponentially() {
+    let policy = ReconnectPolicy::Exponential {
+      initial_delay: Duration::from_millis(100),
+      max_delay: Duration::from_millis(1000),
+      max_attempts: Some(10),
+    };
+
+    assert!(between(policy.delay(1), 50, 100));
+    assert!(between(policy.delay(3), 200, 400));
+    assert!(between(policy.delay(10), 500, 1000));
+    assert_eq!(policy.delay(11), None);
+  }
+
+  #[test]
+  fn backs_off_linearly() {
+    let policy = ReconnectPolicy::Linear {
+      step: Duration::from_millis(100),
+      max_delay: Duration::from_millis(250),
+      max_attempts: None,
+    };
+
+    assert!(between(policy.delay(2), 100, 200));
+    assert!(between(policy.delay(1000), 125, 250));
+    assert_eq!(ReconnectPolicy::None.delay(1), None);
+  }
+
+  #[test]
+  fn runs_jobs_in_order_on_one_thread() {
+    let timer = ReconnectTimer::default();
+    let (tx, rx) = mpsc::channel();
+    for &(delay, id) in &[(60, 3), (20, 1), (40, 2)] {
+      let tx = tx.clone();
+      timer.schedule(Duration::from_millis(delay), move || {
+        tx.send((id, std::thread::current().id())).unwrap()
+      });
+    }
+
+    let ran = rx.iter().take(3).collect::<Vec<_>>();
+    assert_eq!(ran.iter().map(|&(id, _)| id).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert!(ran.iter().all(|&(_, thread)| thread == ran[0].1));
+  }
+}
@@ -0,0 +1,133 @@
+// `std::future::Future` and futures 0.3 `Stream` implementations, so that the client can be used with async/await.
+//
+// Internally everything is still driven by futures 0.1 tasks, so each poll runs the futures 0.1 poll inside a task
+// whose notifications wake the `Waker` of the current `Context`.
+
+use futures::executor::{self, Notify, NotifyHandle};
+use futures::Async;
+use serde::de::DeserializeOwned;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use crate::{
+  ClientError, ConnectionStatus, ConnectionStatusStream, History, Subscription, SubscriptionResults, TransactionFuture,
+  Unsubscribe,
+};
+
+struct WakerNotify(Waker);
+
+impl Notify for WakerNotify {
+  fn notify(&self, _id: usize) {
+    self.0.wake_by_ref();
+  }
+}
+
+fn notify_handle(cx: &Context) -> NotifyHandle {
+  NotifyHandle::from(Arc::new(WakerNotify(cx.waker().clone())))
+}
+
+fn poll_future<F: futures::Future>(future: &mut F, cx: &mut Context) -> Poll<Result<F::Item, F::Error>> {
+  match executor::spawn(future).poll_future_notify(&notify_handle(cx), 0) {
+    Ok(Async::Ready(item)) => Poll::Ready(Ok(item)),
+    Ok(Async::NotReady) => Poll::Pending,
+    Err(e) => Poll::Ready(Err(e)),
+  }
+}
+
+fn poll_stream<S: futures::Stream>(stream: &mut S, cx: &mut Context) -> Poll<Option<Result<S::Item, S::Error>>> {
+  match executor::spawn(stream).poll_stream_notify(&notify_handle(cx), 0) {
+    Ok(Async::Ready(Some(item))) => Poll::Ready(Some(Ok(item))),
+    Ok(Async::Ready(None)) => Poll::Ready(None),
+    Ok(Async::NotReady) => Poll::Pending,
+    Err(e) => Poll::Ready(Some(Err(e))),
+  }
+}
+
+impl<R> std::future::Future for TransactionFuture<R> {
+  type Output = Result<R, ClientError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    poll_future(self.get_mut(), cx)
+  }
+}
+
+impl std::future::Future for Unsubscribe {
+  type Output = Result<(), ClientError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    poll_future(self.get_mut(), cx)
+  }
+}
+
+impl<T: std::fmt::Debug> futures03::Stream for Subscription<T> {
+  type Item = Result<T, ClientError>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    poll_stream(self.get_mut(), cx)
+  }
+}
+
+impl<T: DeserializeOwned> futures03::Stream for History<T> {
+  type Item = Result<crate::HistoryMessage<T>, ClientError>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    poll_stream(self.get_mut(), cx)
+  }
+}
+
+impl<T: std::fmt::Debug> futures03::Stream for SubscriptionResults<T> {
+  type Item = Result<T, ClientError>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    match poll_stream(self.get_mut(), cx) {
+      Poll::Ready(Some(Ok(res))) => Poll::Ready(Some(res)),
+      Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+      Poll::Ready(None) => Poll::Ready(None),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+impl futures03::Stream for ConnectionStatusStream {
+  type Item = ConnectionStatus;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    match poll_stream(self.get_mut(), cx) {
+      Poll::Ready(Some(Ok(status))) => Poll::Ready(Some(status)),
+      Poll::Ready(_) => Poll::Ready(None),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::task::Wake;
+
+  struct Flag(AtomicBool);
+
+  impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+      self.0.store(true, Ordering::SeqCst);
+    }
+  }
+
+  #[test]
+  fn wakes_when_notified() {
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    let (tx, mut rx) = futures::sync::oneshot::channel::<u32>();
+
+    assert_eq!(poll_future(&mut rx, &mut cx), Poll::Pending);
+    assert!(!flag.0.load(Ordering::SeqCst));
+
+    tx.send(1).unwrap();
+
+    assert!(flag.0.load(Ordering::SeqCst));
+    assert_eq!(poll_future(&mut rx, &mut cx), Poll::Ready(Ok(1)));
+  }
+}